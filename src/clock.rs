@@ -0,0 +1,217 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::sync::{Arc,Condvar,Mutex};
+use std::thread;
+use std::time::{Duration,Instant};
+
+/// A task to be run by a Clock
+pub type Task = Box<dyn FnOnce() + Send>;
+
+/// Source of time for request timeouts and maintenance timers
+pub trait Clock: Send + Sync + 'static {
+    /// Returns the time elapsed since the clock was created
+    fn now(&self) -> Duration;
+
+    /// Runs task once delay has elapsed
+    fn schedule(&self, delay: Duration, task: Task);
+}
+
+/// A Clock backed by the operating system's monotonic clock
+///
+/// Scheduled tasks are kept in a heap and run one at a time on a single timer thread, so they
+/// should be quick, and hand anything that blocks off to another thread.
+pub struct SystemClock {
+    start: Instant,
+    timer: Arc<Timer>,
+}
+
+struct Timer {
+    state: Mutex<TimerState>,
+    cond: Condvar,
+}
+
+struct TimerState {
+    seq: u64,
+    tasks: BinaryHeap<Scheduled>,
+    closed: bool,
+}
+
+impl SystemClock {
+    pub fn new() -> SystemClock {
+        let start = Instant::now();
+        let timer = Arc::new(Timer {
+            state: Mutex::new(TimerState {
+                seq: 0,
+                tasks: BinaryHeap::new(),
+                closed: false,
+            }),
+            cond: Condvar::new(),
+        });
+        let timer_thread = timer.clone();
+        thread::spawn(move || {
+            run_timer(start, &timer_thread);
+        });
+        SystemClock {
+            start: start,
+            timer: timer,
+        }
+    }
+}
+
+/// Runs the tasks of timer as they come due, until the clock is dropped
+fn run_timer(start: Instant, timer: &Timer) {
+    let mut state = timer.state.lock().unwrap();
+    loop {
+        if state.closed {
+            return;
+        }
+        let now = start.elapsed();
+        let next_at = state.tasks.peek().map(|next| next.at);
+        match next_at {
+            Some(at) if at <= now => {
+                let next = state.tasks.pop().unwrap();
+                drop(state);
+                (next.task)();
+                state = timer.state.lock().unwrap();
+            }
+            Some(at) => {
+                state = timer.cond.wait_timeout(state, at - now).unwrap().0;
+            }
+            None => {
+                state = timer.cond.wait(state).unwrap();
+            }
+        }
+    }
+}
+
+impl Drop for SystemClock {
+    fn drop(&mut self) {
+        self.timer.state.lock().unwrap().closed = true;
+        self.timer.cond.notify_one();
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> SystemClock {
+        SystemClock::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    fn schedule(&self, delay: Duration, task: Task) {
+        let mut state = self.timer.state.lock().unwrap();
+        let scheduled = Scheduled {
+            at: self.start.elapsed() + delay,
+            seq: state.seq,
+            task: task,
+        };
+        state.seq += 1;
+        state.tasks.push(scheduled);
+        drop(state);
+        self.timer.cond.notify_one();
+    }
+}
+
+/// A Clock that only moves when advance is called
+///
+/// Scheduled tasks run on the thread calling advance, in deadline order (ties in the order they
+/// were scheduled), so a simulation driven by a VirtualClock never waits on real time.
+pub struct VirtualClock {
+    state: Mutex<VirtualState>,
+}
+
+struct VirtualState {
+    now: Duration,
+    seq: u64,
+    tasks: BinaryHeap<Scheduled>,
+}
+
+struct Scheduled {
+    at: Duration,
+    seq: u64,
+    task: Task,
+}
+
+impl PartialEq for Scheduled {
+    fn eq(&self, other: &Scheduled) -> bool {
+        self.at == other.at && self.seq == other.seq
+    }
+}
+
+impl Eq for Scheduled {}
+
+impl PartialOrd for Scheduled {
+    fn partial_cmp(&self, other: &Scheduled) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scheduled {
+    fn cmp(&self, other: &Scheduled) -> Ordering {
+        (other.at, other.seq).cmp(&(self.at, self.seq))
+    }
+}
+
+impl VirtualClock {
+    pub fn new() -> VirtualClock {
+        VirtualClock {
+            state: Mutex::new(VirtualState {
+                now: Duration::from_millis(0),
+                seq: 0,
+                tasks: BinaryHeap::new(),
+            }),
+        }
+    }
+
+    /// Moves time forward by duration, running every task that comes due on the way
+    pub fn advance(&self, duration: Duration) {
+        let target = self.now() + duration;
+        loop {
+            let mut state = self.state.lock().unwrap();
+            let due = match state.tasks.peek() {
+                Some(next) => next.at <= target,
+                None => false,
+            };
+            if !due {
+                state.now = target;
+                return;
+            }
+            let next = state.tasks.pop().unwrap();
+            state.now = next.at;
+            drop(state);
+            (next.task)();
+        }
+    }
+
+    /// Returns the number of tasks waiting to run
+    pub fn pending(&self) -> usize {
+        self.state.lock().unwrap().tasks.len()
+    }
+}
+
+impl Default for VirtualClock {
+    fn default() -> VirtualClock {
+        VirtualClock::new()
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> Duration {
+        self.state.lock().unwrap().now
+    }
+
+    fn schedule(&self, delay: Duration, task: Task) {
+        let mut state = self.state.lock().unwrap();
+        let scheduled = Scheduled {
+            at: state.now + delay,
+            seq: state.seq,
+            task: task,
+        };
+        state.seq += 1;
+        state.tasks.push(scheduled);
+    }
+}
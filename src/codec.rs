@@ -0,0 +1,531 @@
+use std::fmt::Debug;
+use std::str;
+use rustc_serialize::json;
+
+use ::{KEY_LEN,NETWORK_VERSION};
+use ::error::{Error,Result};
+use ::kademlia::{FindValueResult,Reply,Request};
+use ::key::{Distance,Key};
+use ::routing::{NodeAndDistance,NodeInfo};
+use ::rpc::{Message,RpcMessage};
+
+/// Encoding used for messages on the wire
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum WireFormat {
+    /// Compact, length-prefixed binary encoding
+    Binary,
+    /// JSON text, which is easier to read when debugging but much larger
+    Json,
+}
+
+/// Converts RpcMessages to and from datagrams
+pub trait Codec: Debug + Send + Sync {
+    fn encode(&self, rmsg: &RpcMessage) -> Result<Vec<u8>>;
+    fn decode(&self, buf: &[u8]) -> Result<RpcMessage>;
+}
+
+impl WireFormat {
+    pub fn codec(&self) -> Box<dyn Codec> {
+        match *self {
+            WireFormat::Binary => Box::new(BinaryCodec),
+            WireFormat::Json => Box::new(JsonCodec),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    fn encode(&self, rmsg: &RpcMessage) -> Result<Vec<u8>> {
+        json::encode(rmsg).map(String::into_bytes).map_err(|e| Error::Encode(e.to_string()))
+    }
+
+    fn decode(&self, buf: &[u8]) -> Result<RpcMessage> {
+        let buf_str = try!(str::from_utf8(buf).map_err(|e| Error::Decode(e.to_string())));
+        let rmsg = try!(json::decode::<RpcMessage>(buf_str)
+                            .map_err(|e| Error::Decode(e.to_string())));
+        if rmsg.version != NETWORK_VERSION {
+            return Err(Error::WrongVersion(rmsg.version));
+        }
+        Ok(rmsg)
+    }
+}
+
+/// Binary encoding of RpcMessages
+///
+/// A message starts with the network version byte, followed by its fields in declaration order.
+/// Keys are written as their raw bytes, integers are big-endian, enums are a tag byte followed by
+/// their fields, and strings and lists are prefixed by their length as a u16.
+#[derive(Debug)]
+pub struct BinaryCodec;
+
+impl Codec for BinaryCodec {
+    fn encode(&self, rmsg: &RpcMessage) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+        try!(rmsg.write(&mut out));
+        Ok(out)
+    }
+
+    fn decode(&self, buf: &[u8]) -> Result<RpcMessage> {
+        let mut reader = Reader { buf: buf, pos: 0 };
+        let rmsg = try!(RpcMessage::read(&mut reader));
+        if reader.pos != buf.len() {
+            return Err(Error::Decode(format!("{} trailing bytes", buf.len() - reader.pos)));
+        }
+        Ok(rmsg)
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.buf.len() - self.pos < len {
+            return Err(Error::Decode(String::from("message truncated")));
+        }
+        let ret = &self.buf[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(ret)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(try!(self.take(1))[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        let b = try!(self.take(2));
+        Ok((b[0] as u16) << 8 | b[1] as u16)
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        let b = try!(self.take(8));
+        Ok(b.iter().fold(0, |acc, &x| acc << 8 | x as u64))
+    }
+
+    fn key_bytes(&mut self) -> Result<[u8; KEY_LEN]> {
+        let mut ret = [0; KEY_LEN];
+        ret.copy_from_slice(try!(self.take(KEY_LEN)));
+        Ok(ret)
+    }
+}
+
+fn write_u16(out: &mut Vec<u8>, x: u16) {
+    out.push((x >> 8) as u8);
+    out.push(x as u8);
+}
+
+fn write_u64(out: &mut Vec<u8>, x: u64) {
+    for i in (0..8).rev() {
+        out.push((x >> (i * 8)) as u8);
+    }
+}
+
+fn write_len(out: &mut Vec<u8>, len: usize) -> Result<()> {
+    if len > u16::MAX as usize {
+        return Err(Error::Encode(format!("length {} does not fit in a u16", len)));
+    }
+    write_u16(out, len as u16);
+    Ok(())
+}
+
+fn bad_tag(what: &str, tag: u8) -> Error {
+    Error::Decode(format!("unknown {} tag {}", what, tag))
+}
+
+/// A value with a binary wire encoding
+trait Wire: Sized {
+    fn write(&self, out: &mut Vec<u8>) -> Result<()>;
+    fn read(r: &mut Reader) -> Result<Self>;
+}
+
+impl Wire for Key {
+    fn write(&self, out: &mut Vec<u8>) -> Result<()> {
+        out.extend_from_slice(self.as_bytes());
+        Ok(())
+    }
+
+    fn read(r: &mut Reader) -> Result<Key> {
+        r.key_bytes().map(Key::from)
+    }
+}
+
+impl Wire for Distance {
+    fn write(&self, out: &mut Vec<u8>) -> Result<()> {
+        out.extend_from_slice(self.as_bytes());
+        Ok(())
+    }
+
+    fn read(r: &mut Reader) -> Result<Distance> {
+        r.key_bytes().map(Distance::from)
+    }
+}
+
+impl Wire for u8 {
+    fn write(&self, out: &mut Vec<u8>) -> Result<()> {
+        out.push(*self);
+        Ok(())
+    }
+
+    fn read(r: &mut Reader) -> Result<u8> {
+        r.u8()
+    }
+}
+
+impl Wire for String {
+    fn write(&self, out: &mut Vec<u8>) -> Result<()> {
+        try!(write_len(out, self.len()));
+        out.extend_from_slice(self.as_bytes());
+        Ok(())
+    }
+
+    fn read(r: &mut Reader) -> Result<String> {
+        let len = try!(r.u16()) as usize;
+        let bytes = try!(r.take(len));
+        str::from_utf8(bytes).map(String::from).map_err(|e| Error::Decode(e.to_string()))
+    }
+}
+
+impl Wire for Option<u64> {
+    fn write(&self, out: &mut Vec<u8>) -> Result<()> {
+        match *self {
+            None => out.push(0),
+            Some(x) => {
+                out.push(1);
+                write_u64(out, x);
+            }
+        }
+        Ok(())
+    }
+
+    fn read(r: &mut Reader) -> Result<Option<u64>> {
+        match try!(r.u8()) {
+            0 => Ok(None),
+            1 => r.u64().map(Some),
+            tag => Err(bad_tag("option", tag)),
+        }
+    }
+}
+
+impl<T: Wire> Wire for Vec<T> {
+    fn write(&self, out: &mut Vec<u8>) -> Result<()> {
+        try!(write_len(out, self.len()));
+        for x in self {
+            try!(x.write(out));
+        }
+        Ok(())
+    }
+
+    fn read(r: &mut Reader) -> Result<Vec<T>> {
+        let len = try!(r.u16()) as usize;
+        let mut ret = Vec::with_capacity(len);
+        for _ in 0..len {
+            ret.push(try!(T::read(r)));
+        }
+        Ok(ret)
+    }
+}
+
+impl Wire for NodeInfo {
+    fn write(&self, out: &mut Vec<u8>) -> Result<()> {
+        try!(self.id.write(out));
+        try!(self.addr.write(out));
+        self.net_id.write(out)
+    }
+
+    fn read(r: &mut Reader) -> Result<NodeInfo> {
+        Ok(NodeInfo {
+            id: try!(Key::read(r)),
+            addr: try!(String::read(r)),
+            net_id: try!(String::read(r)),
+        })
+    }
+}
+
+impl Wire for NodeAndDistance {
+    fn write(&self, out: &mut Vec<u8>) -> Result<()> {
+        try!(self.0.write(out));
+        self.1.write(out)
+    }
+
+    fn read(r: &mut Reader) -> Result<NodeAndDistance> {
+        let node_info = try!(NodeInfo::read(r));
+        let dist = try!(Distance::read(r));
+        Ok(NodeAndDistance(node_info, dist))
+    }
+}
+
+impl Wire for Request {
+    fn write(&self, out: &mut Vec<u8>) -> Result<()> {
+        match *self {
+            Request::Ping => {
+                out.push(0);
+                Ok(())
+            }
+            Request::Store(ref k, ref v, ttl) => {
+                out.push(1);
+                try!(k.write(out));
+                try!(v.write(out));
+                ttl.write(out)
+            }
+            Request::FindNode(ref id) => {
+                out.push(2);
+                id.write(out)
+            }
+            Request::FindValue(ref k) => {
+                out.push(3);
+                k.write(out)
+            }
+        }
+    }
+
+    fn read(r: &mut Reader) -> Result<Request> {
+        match try!(r.u8()) {
+            0 => Ok(Request::Ping),
+            1 => {
+                let k = try!(Vec::read(r));
+                let v = try!(Vec::read(r));
+                let ttl = try!(Option::<u64>::read(r));
+                Ok(Request::Store(k, v, ttl))
+            }
+            2 => Key::read(r).map(Request::FindNode),
+            3 => Vec::read(r).map(Request::FindValue),
+            tag => Err(bad_tag("request", tag)),
+        }
+    }
+}
+
+impl Wire for FindValueResult {
+    fn write(&self, out: &mut Vec<u8>) -> Result<()> {
+        match *self {
+            FindValueResult::Nodes(ref nodes) => {
+                out.push(0);
+                nodes.write(out)
+            }
+            FindValueResult::Value(ref v, ttl) => {
+                out.push(1);
+                try!(v.write(out));
+                ttl.write(out)
+            }
+        }
+    }
+
+    fn read(r: &mut Reader) -> Result<FindValueResult> {
+        match try!(r.u8()) {
+            0 => Vec::read(r).map(FindValueResult::Nodes),
+            1 => {
+                let v = try!(Vec::read(r));
+                let ttl = try!(Option::<u64>::read(r));
+                Ok(FindValueResult::Value(v, ttl))
+            }
+            tag => Err(bad_tag("find value result", tag)),
+        }
+    }
+}
+
+impl Wire for Reply {
+    fn write(&self, out: &mut Vec<u8>) -> Result<()> {
+        match *self {
+            Reply::Ping => {
+                out.push(0);
+                Ok(())
+            }
+            Reply::FindNode(ref nodes) => {
+                out.push(1);
+                nodes.write(out)
+            }
+            Reply::FindValue(ref res) => {
+                out.push(2);
+                res.write(out)
+            }
+        }
+    }
+
+    fn read(r: &mut Reader) -> Result<Reply> {
+        match try!(r.u8()) {
+            0 => Ok(Reply::Ping),
+            1 => Vec::read(r).map(Reply::FindNode),
+            2 => FindValueResult::read(r).map(Reply::FindValue),
+            tag => Err(bad_tag("reply", tag)),
+        }
+    }
+}
+
+impl Wire for Message {
+    fn write(&self, out: &mut Vec<u8>) -> Result<()> {
+        match *self {
+            Message::Kill => {
+                out.push(0);
+                Ok(())
+            }
+            Message::Request(ref req) => {
+                out.push(1);
+                req.write(out)
+            }
+            Message::Reply(ref rep) => {
+                out.push(2);
+                rep.write(out)
+            }
+        }
+    }
+
+    fn read(r: &mut Reader) -> Result<Message> {
+        match try!(r.u8()) {
+            0 => Ok(Message::Kill),
+            1 => Request::read(r).map(Message::Request),
+            2 => Reply::read(r).map(Message::Reply),
+            tag => Err(bad_tag("message", tag)),
+        }
+    }
+}
+
+impl Wire for RpcMessage {
+    fn write(&self, out: &mut Vec<u8>) -> Result<()> {
+        out.push(self.version);
+        try!(self.token.write(out));
+        try!(self.src.write(out));
+        try!(self.dst.write(out));
+        self.msg.write(out)
+    }
+
+    fn read(r: &mut Reader) -> Result<RpcMessage> {
+        // Nothing past the version byte can be trusted to parse if the version differs
+        let version = try!(r.u8());
+        if version != NETWORK_VERSION {
+            return Err(Error::WrongVersion(version));
+        }
+        Ok(RpcMessage {
+            version: version,
+            token: try!(Key::read(r)),
+            src: try!(NodeInfo::read(r)),
+            dst: try!(NodeInfo::read(r)),
+            msg: try!(Message::read(r)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(i: usize) -> NodeInfo {
+        NodeInfo {
+            id: Key::hash(format!("node {}", i).as_bytes()),
+            addr: format!("sim:{}", i),
+            net_id: String::from("test_net"),
+        }
+    }
+
+    fn rmsg(msg: Message) -> RpcMessage {
+        RpcMessage {
+            version: NETWORK_VERSION,
+            token: Key::hash(b"token"),
+            src: node(1),
+            dst: node(2),
+            msg: msg,
+        }
+    }
+
+    /// One message of every kind, with and without optional fields
+    fn messages() -> Vec<RpcMessage> {
+        let nodes = (3..6).map(|i| NodeAndDistance(node(i), node(i).id.dist(node(1).id)))
+                          .collect::<Vec<_>>();
+        vec![
+            Message::Kill,
+            Message::Request(Request::Ping),
+            Message::Request(Request::Store(b"key".to_vec(), b"value".to_vec(), None)),
+            Message::Request(Request::Store(b"key".to_vec(), vec![], Some(u64::MAX))),
+            Message::Request(Request::FindNode(node(3).id)),
+            Message::Request(Request::FindValue(b"key".to_vec())),
+            Message::Reply(Reply::Ping),
+            Message::Reply(Reply::FindNode(vec![])),
+            Message::Reply(Reply::FindNode(nodes.clone())),
+            Message::Reply(Reply::FindValue(FindValueResult::Nodes(nodes))),
+            Message::Reply(Reply::FindValue(FindValueResult::Value(b"value".to_vec(), None))),
+            Message::Reply(Reply::FindValue(FindValueResult::Value(vec![0; 1000], Some(60000)))),
+        ].into_iter().map(rmsg).collect()
+    }
+
+    fn round_trips(codec: &dyn Codec) {
+        for rmsg in messages() {
+            let buf = codec.encode(&rmsg).unwrap();
+            let decoded = codec.decode(&buf).unwrap();
+            assert_eq!(format!("{:?}", decoded), format!("{:?}", rmsg));
+        }
+    }
+
+    fn assert_rejected(codec: &dyn Codec, buf: &[u8]) {
+        match codec.decode(buf) {
+            Err(Error::Decode(_)) => {}
+            res => panic!("{:?} decoded as {:?}", buf, res),
+        }
+    }
+
+    /// Checks that codec rejects every message cut short or followed by more bytes
+    fn rejects_truncated_and_trailing(codec: &dyn Codec) {
+        for rmsg in messages() {
+            let buf = codec.encode(&rmsg).unwrap();
+            for len in 0..buf.len() {
+                assert_rejected(codec, &buf[..len]);
+            }
+            let mut long = buf.clone();
+            long.push(b'0');
+            assert_rejected(codec, &long);
+        }
+    }
+
+    #[test]
+    fn binary_round_trips() {
+        round_trips(&BinaryCodec);
+    }
+
+    #[test]
+    fn json_round_trips() {
+        round_trips(&JsonCodec);
+    }
+
+    #[test]
+    fn binary_rejects_truncated_and_trailing_bytes() {
+        rejects_truncated_and_trailing(&BinaryCodec);
+    }
+
+    #[test]
+    fn json_rejects_truncated_and_trailing_bytes() {
+        rejects_truncated_and_trailing(&JsonCodec);
+    }
+
+    #[test]
+    fn binary_rejects_unknown_tags() {
+        // Each message ends in the tag being replaced, followed by the given number of bytes
+        let cases = vec![
+            (Message::Kill, 0),
+            (Message::Request(Request::Ping), 0),
+            (Message::Request(Request::Store(vec![], vec![], None)), 0),
+            (Message::Reply(Reply::Ping), 0),
+            (Message::Reply(Reply::FindValue(FindValueResult::Nodes(vec![]))), 2),
+        ];
+        for (msg, after) in cases {
+            let mut buf = BinaryCodec.encode(&rmsg(msg)).unwrap();
+            let tag = buf.len() - 1 - after;
+            buf[tag] = 9;
+            assert_rejected(&BinaryCodec, &buf);
+        }
+    }
+
+    #[test]
+    fn wrong_version_is_rejected() {
+        let codecs: Vec<Box<dyn Codec>> = vec![Box::new(BinaryCodec), Box::new(JsonCodec)];
+        for codec in codecs {
+            let mut old = rmsg(Message::Request(Request::Ping));
+            old.version = NETWORK_VERSION - 1;
+            let buf = codec.encode(&old).unwrap();
+            match codec.decode(&buf) {
+                Err(Error::WrongVersion(version)) => assert_eq!(version, NETWORK_VERSION - 1),
+                res => panic!("{:?} decoded as {:?}", codec, res),
+            }
+        }
+    }
+}
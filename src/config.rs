@@ -0,0 +1,91 @@
+use std::path::PathBuf;
+use std::usize;
+
+use ::codec::WireFormat;
+use ::error::{Error,Result};
+
+/// Tuning parameters of a Kademlia node
+///
+/// All durations are in milliseconds. Starting a node fails with Error::Config if k_param,
+/// a_param, workers, queue_len or any of the maintenance intervals is 0.
+#[derive(Clone,Debug)]
+pub struct Config {
+    /// Entries per bucket, and number of nodes each value is stored on (default 8)
+    pub k_param: usize,
+    /// Number of parallel requests during lookups (default 3)
+    pub a_param: usize,
+    /// Time to wait for a reply before a request fails (default 5s)
+    pub timeout: u64,
+    /// Max message length in bytes (default 8196)
+    pub message_len: usize,
+    /// Encoding of messages on the wire; all nodes of a network must agree (default Binary)
+    pub wire_format: WireFormat,
+    /// Time after which a bucket without lookups is refreshed (default 1h)
+    pub refresh_interval: u64,
+    /// Interval at which stored values are republished to the closest nodes (default 1h)
+    pub republish_interval: u64,
+    /// Interval at which values are republished by their original publisher (default 24h)
+    pub original_republish_interval: u64,
+    /// Expiry of a value cached by get on a node along the lookup path, halved for every node
+    /// between it and the key (default 1h)
+    pub cache_ttl: u64,
+    /// Number of worker threads handling incoming requests (default 4)
+    pub workers: usize,
+    /// Number of tasks that can wait for a free worker; incoming requests beyond this are
+    /// dropped (default 256)
+    pub queue_len: usize,
+    /// Interval between sweeps of expired values from the store (default 1min)
+    pub sweep_interval: u64,
+    /// Max number of values held in the store; Store requests for new keys beyond this are
+    /// ignored (default unlimited)
+    pub max_values: usize,
+    /// File that Kademlia::start persists the node's records to, and reloads them from when the
+    /// node is started again; None keeps them in memory only (default None)
+    pub store_path: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            k_param: 8,
+            a_param: 3,
+            timeout: 5000,
+            message_len: 8196,
+            wire_format: WireFormat::Binary,
+            refresh_interval: 60 * 60 * 1000,
+            republish_interval: 60 * 60 * 1000,
+            original_republish_interval: 24 * 60 * 60 * 1000,
+            cache_ttl: 60 * 60 * 1000,
+            workers: 4,
+            queue_len: 256,
+            sweep_interval: 60 * 1000,
+            max_values: usize::MAX,
+            store_path: None,
+        }
+    }
+}
+
+impl Config {
+    /// Checks that the node can run with this config
+    ///
+    /// A zero maintenance interval would reschedule its task forever without letting time move,
+    /// and without workers or queue slots every request would be dropped.
+    pub fn validate(&self) -> Result<()> {
+        let nonzero = [
+            ("k_param", self.k_param as u64),
+            ("a_param", self.a_param as u64),
+            ("workers", self.workers as u64),
+            ("queue_len", self.queue_len as u64),
+            ("refresh_interval", self.refresh_interval),
+            ("republish_interval", self.republish_interval),
+            ("original_republish_interval", self.original_republish_interval),
+            ("sweep_interval", self.sweep_interval),
+        ];
+        for &(name, value) in &nonzero {
+            if value == 0 {
+                return Err(Error::Config(format!("{} must not be 0", name)));
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,74 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::result;
+
+/// Errors returned by Kademlia operations
+#[derive(Debug)]
+pub enum Error {
+    /// A Config field has a value the node can't run with
+    Config(String),
+    /// Failed to bind the node's socket
+    Bind(io::Error),
+    /// The record store failed to read or write its file
+    Store(io::Error),
+    /// The transport failed to send or receive a message
+    Io(io::Error),
+    /// A message could not be encoded
+    Encode(String),
+    /// A received message or value could not be decoded
+    Decode(String),
+    /// A key could not be parsed from hex
+    InvalidKey(String),
+    /// A message was sent with a different network version
+    WrongVersion(u8),
+    /// A message was sent by a node on a different network
+    WrongNetId(String),
+    /// A message was addressed to a different node
+    WrongDst,
+    /// The remote node didn't reply in time
+    Timeout,
+    /// The remote node replied with a reply that doesn't match the request
+    UnexpectedReply,
+    /// A channel between the node's threads was closed
+    ChannelClosed,
+    /// The node has been shut down
+    Shutdown,
+    /// A value was acknowledged by fewer nodes than required: (acknowledgements, required)
+    QuorumNotMet(usize, usize),
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Config(ref e) => write!(f, "invalid config: {}", e),
+            Error::Bind(ref e) => write!(f, "failed to bind socket: {}", e),
+            Error::Store(ref e) => write!(f, "record store error: {}", e),
+            Error::Io(ref e) => write!(f, "transport error: {}", e),
+            Error::Encode(ref e) => write!(f, "failed to encode message: {}", e),
+            Error::Decode(ref e) => write!(f, "failed to decode: {}", e),
+            Error::InvalidKey(ref e) => write!(f, "invalid key: {}", e),
+            Error::WrongVersion(v) => write!(f, "message has network version {}", v),
+            Error::WrongNetId(ref net_id) => write!(f, "message is from net_id {}", net_id),
+            Error::WrongDst => write!(f, "message is addressed to a different node"),
+            Error::Timeout => write!(f, "request timed out"),
+            Error::UnexpectedReply => write!(f, "reply does not match request"),
+            Error::ChannelClosed => write!(f, "channel closed"),
+            Error::Shutdown => write!(f, "node has been shut down"),
+            Error::QuorumNotMet(acks, required) => {
+                write!(f, "value stored on {} nodes, {} required", acks, required)
+            }
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Bind(ref e) | Error::Store(ref e) | Error::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
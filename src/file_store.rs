@@ -0,0 +1,518 @@
+use std::collections::HashMap;
+use std::fs;
+use std::fs::{File,OpenOptions};
+use std::io;
+use std::io::{Read,Write};
+use std::path::{Path,PathBuf};
+use std::sync::Arc;
+use std::time::{Duration,SystemTime,UNIX_EPOCH};
+
+use ::clock::Clock;
+use ::error::{Error,Result};
+use ::store::{Record,RecordStore};
+
+/// Number of dead entries the log may hold beyond its live records before it is compacted
+const COMPACT_SLACK: usize = 1000;
+
+const PUT: u8 = 0;
+const REMOVE: u8 = 1;
+
+/// A RecordStore that keeps its records in memory, and persists them to an append-only log,
+/// synced on every write, so they survive restarts and crashes
+///
+/// Times are stored as wall-clock milliseconds, so TTLs keep running while the node is down.
+pub struct FileStore {
+    path: PathBuf,
+    file: File,
+    clock: Arc<dyn Clock>,
+    records: HashMap<Vec<u8>, Record>,
+    /// Number of entries in the log, live or not
+    entries: usize,
+}
+
+impl FileStore {
+    /// Opens the log at path, creating it if it doesn't exist, and loads its records
+    pub fn open<P: AsRef<Path>>(path: P, clock: Arc<dyn Clock>) -> Result<FileStore> {
+        let path = path.as_ref().to_path_buf();
+        let mut options = OpenOptions::new();
+        options.read(true).append(true).create(true);
+        let mut file = try!(options.open(&path).map_err(Error::Store));
+        let mut buf = Vec::new();
+        try!(file.read_to_end(&mut buf).map_err(Error::Store));
+
+        let mut store = FileStore {
+            path: path,
+            file: file,
+            clock: clock,
+            records: HashMap::new(),
+            entries: 0,
+        };
+        let valid = try!(store.replay(&buf));
+        if valid < buf.len() {
+            warn!("Truncating {} bytes of incomplete or corrupt entries from {}",
+                  buf.len() - valid, store.path.display());
+            try!(store.file.set_len(valid as u64).map_err(Error::Store));
+        }
+        let now = store.clock.now();
+        store.records.retain(|_, record| !record.is_expired(now));
+        info!("Loaded {} records from {}", store.records.len(), store.path.display());
+        Ok(store)
+    }
+
+    /// Applies the entries in buf, returning the length of the valid prefix
+    ///
+    /// Appends are sequential, so only the last entry can have been torn by a crash: one that runs
+    /// to or past the end of buf, with no valid entry anywhere after its start. Any other bad entry
+    /// means the log is damaged, and fails the replay.
+    fn replay(&mut self, buf: &[u8]) -> Result<usize> {
+        let mut pos = 0;
+        while pos < buf.len() {
+            match read_entry(buf, pos) {
+                Entry::Valid(payload, next) => {
+                    if let Err(e) = self.apply(payload) {
+                        return Err(self.damaged(pos, &e.to_string()));
+                    }
+                    self.entries += 1;
+                    pos = next;
+                }
+                Entry::Corrupt(next) if next < buf.len() => {
+                    return Err(self.damaged(pos, "checksum mismatch"));
+                }
+                Entry::Corrupt(_) | Entry::Incomplete => {
+                    // A damaged length can make an entry in the middle look like the last one
+                    let after = (pos + 1..buf.len()).find(|&p| read_entry(buf, p).is_valid());
+                    if let Some(after) = after {
+                        return Err(self.damaged(pos, &format!("valid entry follows at offset {}",
+                                                              after)));
+                    }
+                    warn!("Torn entry at offset {} of {}", pos, self.path.display());
+                    break;
+                }
+            }
+        }
+        Ok(pos)
+    }
+
+    fn damaged(&self, pos: usize, why: &str) -> Error {
+        Error::Store(io::Error::new(io::ErrorKind::InvalidData,
+                                   format!("bad entry at offset {} of {}: {}", pos,
+                                           self.path.display(), why)))
+    }
+
+    fn apply(&mut self, payload: &[u8]) -> Result<()> {
+        let mut r = Reader { buf: payload };
+        match try!(r.u8()) {
+            PUT => {
+                let k = try!(r.bytes()).to_vec();
+                let value = try!(r.bytes()).to_vec();
+                let stored_at = self.wall_to_clock(try!(r.u64()));
+                let expires = match try!(r.u8()) {
+                    0 => None,
+                    _ => Some(self.wall_to_clock(try!(r.u64()))),
+                };
+                self.records.insert(k, Record {
+                    value: value,
+                    stored_at: stored_at,
+                    expires: expires,
+                });
+            }
+            REMOVE => {
+                let k = try!(r.bytes());
+                self.records.remove(k);
+            }
+            tag => { return Err(Error::Decode(format!("unknown log entry tag {}", tag))); }
+        }
+        Ok(())
+    }
+
+    fn append(&mut self, payload: &[u8]) -> Result<()> {
+        let mut entry = Vec::with_capacity(payload.len() + 8);
+        write_entry(&mut entry, payload);
+        try!(self.file.write_all(&entry).map_err(Error::Store));
+        try!(self.file.sync_data().map_err(Error::Store));
+        self.entries += 1;
+        Ok(())
+    }
+
+    fn put_payload(&self, k: &[u8], record: &Record) -> Vec<u8> {
+        let mut payload = vec![PUT];
+        write_bytes(&mut payload, k);
+        write_bytes(&mut payload, &record.value);
+        write_u64(&mut payload, self.clock_to_wall(record.stored_at));
+        match record.expires {
+            None => payload.push(0),
+            Some(expires) => {
+                payload.push(1);
+                write_u64(&mut payload, self.clock_to_wall(expires));
+            }
+        }
+        payload
+    }
+
+    /// Rewrites the log with only the live records, once it has grown enough to be worth it
+    fn maybe_compact(&mut self) -> Result<()> {
+        if self.entries <= 2 * self.records.len() + COMPACT_SLACK {
+            return Ok(());
+        }
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+
+        let mut buf = Vec::new();
+        for (k, record) in &self.records {
+            write_entry(&mut buf, &self.put_payload(k, record));
+        }
+        let mut tmp = try!(File::create(&tmp_path).map_err(Error::Store));
+        try!(tmp.write_all(&buf).map_err(Error::Store));
+        try!(tmp.sync_all().map_err(Error::Store));
+        try!(fs::rename(&tmp_path, &self.path).map_err(Error::Store));
+
+        self.file = try!(OpenOptions::new().append(true).open(&self.path).map_err(Error::Store));
+        debug!("Compacted {} from {} to {} entries", self.path.display(), self.entries,
+               self.records.len());
+        self.entries = self.records.len();
+        Ok(())
+    }
+
+    /// Converts a time on the node's clock to milliseconds since the Unix epoch
+    fn clock_to_wall(&self, t: Duration) -> u64 {
+        let now = self.clock.now();
+        let wall = unix_millis();
+        if t <= now {
+            wall.saturating_sub(millis(now - t))
+        } else {
+            wall.saturating_add(millis(t - now))
+        }
+    }
+
+    /// Converts milliseconds since the Unix epoch to a time on the node's clock, or 0 if that
+    /// would be before the clock started
+    fn wall_to_clock(&self, wall: u64) -> Duration {
+        let now = self.clock.now();
+        let wall_now = unix_millis();
+        if wall <= wall_now {
+            now.checked_sub(Duration::from_millis(wall_now - wall))
+               .unwrap_or_else(|| Duration::from_millis(0))
+        } else {
+            now + Duration::from_millis(wall - wall_now)
+        }
+    }
+}
+
+impl RecordStore for FileStore {
+    fn get(&mut self, k: &[u8]) -> Result<Option<Record>> {
+        Ok(self.records.get(k).cloned())
+    }
+
+    fn put(&mut self, k: Vec<u8>, record: Record) -> Result<()> {
+        let payload = self.put_payload(&k, &record);
+        try!(self.append(&payload));
+        self.records.insert(k, record);
+        self.maybe_compact()
+    }
+
+    fn remove(&mut self, k: &[u8]) -> Result<Option<Record>> {
+        if !self.records.contains_key(k) {
+            return Ok(None);
+        }
+        let mut payload = vec![REMOVE];
+        write_bytes(&mut payload, k);
+        try!(self.append(&payload));
+        let record = self.records.remove(k);
+        try!(self.maybe_compact());
+        Ok(record)
+    }
+
+    fn keys(&mut self) -> Result<Vec<Vec<u8>>> {
+        Ok(self.records.keys().cloned().collect())
+    }
+
+    /// Expired records stay in the log until the next compaction, and are skipped when it's
+    /// loaded
+    fn expire(&mut self, now: Duration) -> Result<usize> {
+        let before = self.records.len();
+        self.records.retain(|_, record| !record.is_expired(now));
+        let expired = before - self.records.len();
+        try!(self.maybe_compact());
+        Ok(expired)
+    }
+
+    fn len(&self) -> usize {
+        self.records.len()
+    }
+}
+
+/// An entry of the log, as found by read_entry
+///
+/// An entry is its payload's length as a big-endian u32, then the CRC32 of that length and the
+/// payload, then the payload.
+enum Entry<'a> {
+    /// An entry with a matching checksum: its payload, and the position of the next entry
+    Valid(&'a [u8], usize),
+    /// An entry whose checksum doesn't match, and the position the next entry would be at
+    Corrupt(usize),
+    /// An entry that runs past the end of the log
+    Incomplete,
+}
+
+impl<'a> Entry<'a> {
+    fn is_valid(&self) -> bool {
+        match *self {
+            Entry::Valid(..) => true,
+            _ => false,
+        }
+    }
+}
+
+/// Reads the entry at pos in buf
+fn read_entry<'a>(buf: &'a [u8], pos: usize) -> Entry<'a> {
+    let mut r = Reader { buf: &buf[pos..] };
+    let header = r.u32().and_then(|len| r.u32().map(|crc| (len as usize, crc)));
+    let (len, crc) = match header {
+        Ok(header) => header,
+        Err(_) => { return Entry::Incomplete; }
+    };
+    let payload = match r.take(len) {
+        Ok(payload) => payload,
+        Err(_) => { return Entry::Incomplete; }
+    };
+    if entry_crc(payload) != crc {
+        return Entry::Corrupt(pos + 8 + len);
+    }
+    Entry::Valid(payload, pos + 8 + len)
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.buf.len() < n {
+            return Err(Error::Decode(String::from("entry truncated")));
+        }
+        let (ret, rest) = self.buf.split_at(n);
+        self.buf = rest;
+        Ok(ret)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        self.take(4).map(|b| b.iter().fold(0, |acc, &x| acc << 8 | x as u32))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        self.take(8).map(|b| b.iter().fold(0, |acc, &x| acc << 8 | x as u64))
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8]> {
+        let len = try!(self.u32()) as usize;
+        self.take(len)
+    }
+}
+
+fn write_entry(out: &mut Vec<u8>, payload: &[u8]) {
+    write_u32(out, payload.len() as u32);
+    write_u32(out, entry_crc(payload));
+    out.extend_from_slice(payload);
+}
+
+/// Checksum of an entry, covering its length as well as its payload
+fn entry_crc(payload: &[u8]) -> u32 {
+    let mut len = Vec::with_capacity(4);
+    write_u32(&mut len, payload.len() as u32);
+    crc32(&[&len, payload])
+}
+
+fn write_u32(out: &mut Vec<u8>, x: u32) {
+    for i in (0..4).rev() {
+        out.push((x >> (i * 8)) as u8);
+    }
+}
+
+fn write_u64(out: &mut Vec<u8>, x: u64) {
+    for i in (0..8).rev() {
+        out.push((x >> (i * 8)) as u8);
+    }
+}
+
+fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    write_u32(out, bytes.len() as u32);
+    out.extend_from_slice(bytes);
+}
+
+/// CRC-32 (IEEE) of parts, one after the other
+fn crc32(parts: &[&[u8]]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in parts.iter().flat_map(|part| part.iter()) {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn millis(d: Duration) -> u64 {
+    d.as_secs().saturating_mul(1000).saturating_add(d.subsec_nanos() as u64 / 1_000_000)
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(millis).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::OpenOptions;
+    use std::io::{Seek,SeekFrom,Write};
+    use std::process;
+    use std::sync::atomic::{AtomicUsize,Ordering};
+
+    use super::*;
+    use ::clock::VirtualClock;
+
+    /// Returns a path in the temp dir no other test uses, removing anything left there
+    fn temp_path() -> PathBuf {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let path = env::temp_dir().join(format!("kademlia-file-store-{}-{}", process::id(),
+                                                NEXT.fetch_add(1, Ordering::SeqCst)));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn record(value: &[u8], expires: Option<Duration>) -> Record {
+        Record {
+            value: value.to_vec(),
+            stored_at: Duration::from_millis(0),
+            expires: expires,
+        }
+    }
+
+    #[test]
+    fn far_future_expiry_saturates() {
+        let path = temp_path();
+        let clock: Arc<dyn Clock> = Arc::new(VirtualClock::new());
+        let mut store = FileStore::open(&path, clock.clone()).unwrap();
+        let forever = Some(Duration::from_secs(u64::MAX));
+        store.put(b"k".to_vec(), record(b"v", forever)).unwrap();
+        drop(store);
+
+        let mut store = FileStore::open(&path, clock).unwrap();
+        let loaded = store.get(b"k").unwrap().unwrap();
+        assert_eq!(loaded.value, b"v".to_vec());
+        assert!(loaded.expires.unwrap() > Duration::from_secs(1 << 40));
+        let _ = fs::remove_file(&path);
+    }
+
+    /// Writes three records to a new log at path, returning the log's length after each
+    fn write_three(path: &Path, clock: &Arc<dyn Clock>) -> Vec<u64> {
+        let mut store = FileStore::open(path, clock.clone()).unwrap();
+        let mut lens = Vec::new();
+        for &k in &[b"a", b"b", b"c"] {
+            store.put(k.to_vec(), record(k, None)).unwrap();
+            lens.push(fs::metadata(path).unwrap().len());
+        }
+        lens
+    }
+
+    fn keys(store: &mut FileStore) -> Vec<Vec<u8>> {
+        let mut keys = store.keys().unwrap();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn torn_tail_is_truncated() {
+        let path = temp_path();
+        let clock: Arc<dyn Clock> = Arc::new(VirtualClock::new());
+        let lens = write_three(&path, &clock);
+
+        // A crash midway through writing the last entry
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(lens[2] - 3).unwrap();
+        let mut store = FileStore::open(&path, clock.clone()).unwrap();
+        assert_eq!(keys(&mut store), vec![b"a".to_vec(), b"b".to_vec()]);
+        assert_eq!(fs::metadata(&path).unwrap().len(), lens[1]);
+
+        // Entries appended after recovery are read back
+        store.put(b"d".to_vec(), record(b"d", None)).unwrap();
+        drop(store);
+        let mut store = FileStore::open(&path, clock.clone()).unwrap();
+        assert_eq!(keys(&mut store), vec![b"a".to_vec(), b"b".to_vec(), b"d".to_vec()]);
+
+        // A crash partway into the header of the next entry
+        drop(store);
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[0, 0, 1]).unwrap();
+        drop(file);
+        let mut store = FileStore::open(&path, clock).unwrap();
+        assert_eq!(keys(&mut store).len(), 3);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn corrupt_last_entry_is_truncated() {
+        let path = temp_path();
+        let clock: Arc<dyn Clock> = Arc::new(VirtualClock::new());
+        let lens = write_three(&path, &clock);
+
+        let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(lens[2] - 1)).unwrap();
+        file.write_all(&[0xff]).unwrap();
+        drop(file);
+
+        let mut store = FileStore::open(&path, clock).unwrap();
+        assert_eq!(keys(&mut store), vec![b"a".to_vec(), b"b".to_vec()]);
+        assert_eq!(fs::metadata(&path).unwrap().len(), lens[1]);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn corrupt_entry_mid_log_fails_open() {
+        let path = temp_path();
+        let clock: Arc<dyn Clock> = Arc::new(VirtualClock::new());
+        let lens = write_three(&path, &clock);
+
+        // Damage the payload of the middle entry, leaving its length intact
+        let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(lens[1] - 1)).unwrap();
+        file.write_all(&[0xff]).unwrap();
+        drop(file);
+
+        match FileStore::open(&path, clock) {
+            Err(Error::Store(_)) => {}
+            Err(e) => panic!("open failed with {}", e),
+            Ok(_) => panic!("open succeeded"),
+        }
+        // Nothing was truncated, so the log can still be repaired by hand
+        assert_eq!(fs::metadata(&path).unwrap().len(), lens[2]);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn corrupt_length_mid_log_fails_open() {
+        let clock: Arc<dyn Clock> = Arc::new(VirtualClock::new());
+        for &entry in &[0, 1] {
+            let path = temp_path();
+            let lens = write_three(&path, &clock);
+
+            // Make the entry's length claim more than the rest of the log
+            let start = if entry == 0 { 0 } else { lens[entry - 1] };
+            let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+            file.seek(SeekFrom::Start(start)).unwrap();
+            file.write_all(&[0x01]).unwrap();
+            drop(file);
+
+            match FileStore::open(&path, clock.clone()) {
+                Err(Error::Store(_)) => {}
+                Err(e) => panic!("open failed with {}", e),
+                Ok(_) => panic!("open of a log with entry {} damaged succeeded", entry),
+            }
+            assert_eq!(fs::metadata(&path).unwrap().len(), lens[2]);
+            let _ = fs::remove_file(&path);
+        }
+    }
+}
@@ -1,28 +1,42 @@
-use std::collections::{HashMap,HashSet,BinaryHeap};
+use std::collections::{HashMap,HashSet};
 use std::net::UdpSocket;
 use std::sync::{Arc,Mutex};
+use std::sync::atomic::{AtomicBool,Ordering};
 use std::sync::mpsc;
 use std::sync::mpsc::Receiver;
 use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
 use rustc_serialize::{Decoder,Encodable,Encoder};
 
-use ::{A_PARAM,K_PARAM};
+use ::clock::{Clock,SystemClock,Task};
+use ::config::Config;
+use ::error::{Error,Result};
+use ::file_store::FileStore;
 use ::key::Key;
+use ::lookup::{Lookup,LookupResult,LookupTrace,Query};
+use ::pool::{ThreadPool,join_thread};
+use ::promise::Promise;
+use ::rng::{Rng,SystemRng};
 use ::rpc::{ReqHandle,Rpc};
-use ::routing::{NodeAndDistance,NodeInfo,RoutingTable};
+use ::routing::{NodeAndDistance,NodeInfo,RoutingTable,Update};
+use ::store::{MemoryStore,Record,RecordStore};
+use ::transport::Transport;
 
 #[derive(Clone,Debug,RustcEncodable,RustcDecodable)]
 pub enum Request {
     Ping,
-    Store(String, String),
+    /// Stores a key/value pair, optionally expiring after a TTL in milliseconds
+    Store(Vec<u8>, Vec<u8>, Option<u64>),
     FindNode(Key),
-    FindValue(String),
+    FindValue(Vec<u8>),
 }
 
 #[derive(Clone,Debug,RustcEncodable,RustcDecodable)]
 pub enum FindValueResult {
     Nodes(Vec<NodeAndDistance>),
-    Value(String),
+    /// The value, and the milliseconds it has left before it expires if it was stored with a TTL
+    Value(Vec<u8>, Option<u64>),
 }
 
 #[derive(Clone,Debug,RustcEncodable,RustcDecodable)]
@@ -32,300 +46,818 @@ pub enum Reply {
     FindValue(FindValueResult),
 }
 
+/// A value reconciled from several replicas by get_quorum
+#[derive(Clone,Debug)]
+pub struct QuorumValue {
+    /// The value returned by most replicas (on a tie, the one returned by the replica closest to
+    /// the key), or None if no replica had one
+    pub value: Option<Vec<u8>>,
+    /// Number of replicas that returned a value
+    pub replicas: usize,
+    /// Replicas that returned a different value, and the value they returned. They are only
+    /// repaired, by being sent value, when more than half of the replicas returned it.
+    pub dissenters: Vec<(NodeInfo, Vec<u8>)>,
+}
+
+/// A value this node originally published with put
+#[derive(Clone,Debug)]
+struct Publication {
+    value: Vec<u8>,
+    /// Time the value was last published
+    published_at: Duration,
+    /// Time after which the value is no longer republished, if it was put with a TTL
+    expires: Option<Duration>,
+}
+
+/// Longest TTL a value is stored with, in milliseconds (100 years); longer ones are cut down to it
+const MAX_TTL: u64 = 100 * 365 * 24 * 60 * 60 * 1000;
+
+/// Returns the TTL left before expires, in milliseconds
+fn remaining_ttl(expires: Option<Duration>, now: Duration) -> Option<u64> {
+    expires.map(|expires| {
+        let left = if expires > now { expires - now } else { Duration::from_millis(0) };
+        left.as_secs() * 1000 + left.subsec_nanos() as u64 / 1_000_000
+    })
+}
+
 #[derive(Clone)]
 pub struct Kademlia {
     routes: Arc<Mutex<RoutingTable>>,
-    store: Arc<Mutex<HashMap<String, String>>>,
+    /// Buckets whose least recently seen entry is being pinged to decide whether to evict it
+    evicting: Arc<Mutex<HashSet<usize>>>,
+    store: Arc<Mutex<Box<dyn RecordStore>>>,
+    published: Arc<Mutex<HashMap<Vec<u8>, Publication>>>,
+    /// Time each stored value was last republished by this node, kept out of the store so
+    /// republishing doesn't rewrite its records
+    republished: Arc<Mutex<HashMap<Vec<u8>, Duration>>>,
     rpc: Arc<Rpc>,
+    clock: Arc<dyn Clock>,
+    rng: Arc<dyn Rng>,
+    config: Arc<Config>,
     node_info: NodeInfo,
+    pool: Arc<ThreadPool>,
+    closed: Arc<AtomicBool>,
+    handler_thread: Arc<Mutex<Option<JoinHandle<()>>>>,
+    /// Thread of the republish in progress, if any
+    republish_thread: Arc<Mutex<Option<JoinHandle<()>>>>,
+    /// The node as seen by its maintenance timers; emptied on shutdown, so timers still waiting to
+    /// fire don't keep the node's clock, store and routes alive
+    maintenance: Arc<Mutex<Option<Kademlia>>>,
+    /// Held by the handles given out by start, but not by the node's own background tasks
+    _guard: Option<Arc<ShutdownGuard>>,
+}
+
+/// Shuts the node down once the last handle to it is dropped
+struct ShutdownGuard {
+    node: Kademlia,
+}
+
+impl Drop for ShutdownGuard {
+    fn drop(&mut self) {
+        self.node.shutdown();
+    }
 }
 
 /// A Kademlia node
 impl Kademlia {
-    pub fn start(net_id: String, node_id: Key, node_addr: &str, bootstrap: Option<NodeInfo>) -> Kademlia {
-        let socket = UdpSocket::bind(node_addr).unwrap(); // err: failed to bind to socket
+    pub fn start(net_id: String, node_id: Key, node_addr: &str, bootstrap: Option<NodeInfo>,
+                 config: Config) -> Result<Kademlia> {
+        try!(config.validate());
+        let socket = try!(UdpSocket::bind(node_addr).map_err(Error::Bind));
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock::new());
+        let store: Box<dyn RecordStore> = match config.store_path {
+            Some(ref path) => Box::new(try!(FileStore::open(path, clock.clone()))),
+            None => Box::new(MemoryStore::new()),
+        };
+        Kademlia::start_with_transport(net_id, node_id, socket, clock, Arc::new(SystemRng), store,
+                                       bootstrap, config)
+    }
+
+    /// Starts a node that sends and receives its messages over transport, keeps time with clock,
+    /// draws request tokens and refresh targets from rng, and holds values in store
+    ///
+    /// Fails with Error::Config if config doesn't pass Config::validate.
+    pub fn start_with_transport<T: Transport>(net_id: String, node_id: Key, transport: T,
+                                              clock: Arc<dyn Clock>,
+                                              rng: Arc<dyn Rng>,
+                                              store: Box<dyn RecordStore>,
+                                              bootstrap: Option<NodeInfo>,
+                                              config: Config) -> Result<Kademlia> {
+        try!(config.validate());
         let node_info = NodeInfo {
             id: node_id.clone(),
-            addr: socket.local_addr().unwrap().to_string(), // err: failed to retrieve local addr
+            addr: try!(transport.local_addr().map_err(Error::Io)),
             net_id: net_id,
         };
-        let mut routes = RoutingTable::new(node_info.clone());
+        let mut routes = RoutingTable::new(node_info.clone(), config.k_param);
         if let Some(bootstrap) = bootstrap {
             routes.update(bootstrap);
         }
         info!("New node created at {} with ID {:?}", &node_info.addr, &node_info.id);
 
         let (tx, rx) = mpsc::channel();
-        let rpc = Rpc::open(socket, tx, node_info.clone());
+        let rpc = Rpc::open(transport, clock.clone(), rng.clone(), tx, node_info.clone(), &config);
 
         let node = Kademlia {
             routes: Arc::new(Mutex::new(routes)),
-            store: Arc::new(Mutex::new(HashMap::new())),
+            evicting: Arc::new(Mutex::new(HashSet::new())),
+            store: Arc::new(Mutex::new(store)),
+            published: Arc::new(Mutex::new(HashMap::new())),
+            republished: Arc::new(Mutex::new(HashMap::new())),
             node_info: node_info,
             rpc: Arc::new(rpc),
+            clock: clock,
+            rng: rng,
+            pool: Arc::new(ThreadPool::new(config.workers, config.queue_len)),
+            config: Arc::new(config),
+            closed: Arc::new(AtomicBool::new(false)),
+            handler_thread: Arc::new(Mutex::new(None)),
+            republish_thread: Arc::new(Mutex::new(None)),
+            maintenance: Arc::new(Mutex::new(None)),
+            _guard: None,
+        };
+
+        let handler_thread = node.clone().start_req_handler(rx);
+        *node.handler_thread.lock().unwrap() = Some(handler_thread);
+
+        *node.maintenance.lock().unwrap() = Some(node.clone());
+        node.schedule_refresh();
+        node.schedule_sweep();
+        node.schedule_republish();
+
+        let handle = Kademlia {
+            _guard: Some(Arc::new(ShutdownGuard { node: node.clone() })),
+            ..node
         };
 
-        node.clone().start_req_handler(rx);
+        if let Err(e) = handle.lookup_nodes(node_id) {
+            warn!("Bootstrap lookup failed: {}", e);
+        }
+
+        Ok(handle)
+    }
+
+    /// Stops the node
+    ///
+    /// The receive loop exits, pending requests fail with Error::Shutdown, the request handler,
+    /// worker and republish threads are joined, maintenance timers let go of the node, and the
+    /// transport is released. This also happens when the last handle to the node is dropped.
+    pub fn shutdown(&self) {
+        if self.closed.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        info!("Shutting down node {:?}", self.node_info.id);
+        self.maintenance.lock().unwrap().take();
+        self.rpc.shutdown();
+        if let Some(handler_thread) = self.handler_thread.lock().unwrap().take() {
+            join_thread(handler_thread);
+        }
+        if let Some(republish_thread) = self.republish_thread.lock().unwrap().take() {
+            join_thread(republish_thread);
+        }
+        self.pool.join();
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    /// Returns a handle for the node's own threads, which doesn't keep the node running
+    fn worker(&self) -> Kademlia {
+        Kademlia {
+            _guard: None,
+            ..self.clone()
+        }
+    }
+
+    /// Runs task on the worker pool, or drops it if the pool's queue is full
+    fn execute(&self, what: &str, task: Task) {
+        if self.pool.execute(task).is_err() {
+            warn!("Worker queue is full, dropping {}.", what);
+        }
+    }
+
+    /// Runs task with the node after delay, unless the node has shut down by then
+    fn schedule_maintenance<F>(&self, delay: u64, task: F)
+        where F: FnOnce(Kademlia) + Send + 'static {
+        let maintenance = self.maintenance.clone();
+        self.clock.schedule(Duration::from_millis(delay), Box::new(move || {
+            let node = maintenance.lock().unwrap().clone();
+            if let Some(node) = node {
+                task(node);
+            }
+        }));
+    }
+
+    /// Republishes values every republish_interval, on a thread of its own since replicating
+    /// every value takes a while. A republish still running when the next one is due delays it
+    /// to the following interval.
+    fn schedule_republish(&self) {
+        self.schedule_maintenance(self.config.republish_interval, |node| {
+            let mut republish_thread = node.republish_thread.lock().unwrap();
+            let running = republish_thread.as_ref().map_or(false, |thread| !thread.is_finished());
+            // Checked under the lock, so shutdown joins any thread started here
+            if !running && !node.is_closed() {
+                let worker = node.clone();
+                *republish_thread = Some(thread::spawn(move || {
+                    worker.republish();
+                }));
+            }
+            drop(republish_thread);
+            node.schedule_republish();
+        });
+    }
+
+    /// Re-stores values on the nodes currently closest to their keys
+    ///
+    /// Stored values this node is responsible for, being one of the k_param closest nodes it
+    /// knows to their key, are republished once they haven't been stored or republished for
+    /// republish_interval; a value that another node stored here in the meantime was just
+    /// republished by that node, so it is skipped. Copies cached away from the key aren't
+    /// republished, and expire with their TTL. Values put by this node are also published again
+    /// every original_republish_interval, until their TTL runs out.
+    fn republish(&self) {
+        let now = self.clock.now();
+        let republish_interval = Duration::from_millis(self.config.republish_interval);
+        let original_republish_interval = Duration::from_millis(self.config.original_republish_interval);
+        let mut to_publish = Vec::new();
+
+        // Only read the store here, and replicate once its lock is released
+        let mut records = Vec::new();
+        let mut store = self.store.lock().unwrap();
+        let keys = match store.keys() {
+            Ok(keys) => keys,
+            Err(e) => {
+                warn!("Failed to list the store: {}", e);
+                Vec::new()
+            }
+        };
+        for k in keys {
+            match store.get(&k) {
+                Ok(Some(record)) => { records.push((k, record)); }
+                Ok(None) => {}
+                Err(e) => { warn!("Failed to read record from the store: {}", e); }
+            }
+        }
+        drop(store);
+
+        let mut republished = self.republished.lock().unwrap();
+        let held = records.iter().map(|record| record.0.clone()).collect::<HashSet<_>>();
+        republished.retain(|k, _| held.contains(k));
+        for (k, record) in records {
+            let last = republished.get(&k).map_or(record.stored_at, |&at| at.max(record.stored_at));
+            if record.is_expired(now) || now < last + republish_interval {
+                continue;
+            }
+            let closest = self.routes.lock().unwrap()
+                              .closest_nodes(Key::hash(&k), self.config.k_param);
+            if !closest.iter().any(|entry| entry.0.id == self.node_info.id) {
+                continue;
+            }
+            republished.insert(k.clone(), now);
+            to_publish.push((k, record.value, remaining_ttl(record.expires, now)));
+        }
+        drop(republished);
+
+        let mut published = self.published.lock().unwrap();
+        published.retain(|_, publication| {
+            match publication.expires {
+                Some(expires) => now < expires,
+                None => true,
+            }
+        });
+        for (k, publication) in published.iter_mut() {
+            if now < publication.published_at + original_republish_interval {
+                continue;
+            }
+            publication.published_at = now;
+            to_publish.push((k.clone(), publication.value.clone(), remaining_ttl(publication.expires, now)));
+        }
+        drop(published);
+
+        if !to_publish.is_empty() {
+            debug!("Republishing {} values", to_publish.len());
+        }
+        // One value at a time, so a large store doesn't start a flood of lookups at once
+        for (k, v, ttl) in to_publish {
+            if self.is_closed() {
+                return;
+            }
+            let _ = self.replicate(k, v, ttl).wait();
+        }
+    }
+
+    /// Drops expired values from the store every sweep_interval
+    fn schedule_sweep(&self) {
+        self.schedule_maintenance(self.config.sweep_interval, |node| {
+            let now = node.clock.now();
+            let mut store = node.store.lock().unwrap();
+            let swept = store.expire(now);
+            drop(store);
+            match swept {
+                Ok(0) => {}
+                Ok(swept) => { debug!("Swept {} expired values from the store", swept); }
+                Err(e) => { warn!("Failed to sweep the store: {}", e); }
+            }
+            node.schedule_sweep();
+        });
+    }
+
+    /// Checks for stale buckets every refresh_interval
+    fn schedule_refresh(&self) {
+        self.schedule_maintenance(self.config.refresh_interval, |node| {
+            node.refresh_buckets();
+            node.schedule_refresh();
+        });
+    }
 
-        node.lookup_nodes(node_id);
+    /// Looks up a random key in the range of every bucket that hasn't seen a lookup in the last
+    /// refresh_interval. Empty buckets are skipped.
+    fn refresh_buckets(&self) {
+        let routes = self.routes.lock().unwrap();
+        let stale = routes.stale_buckets(self.clock.now(), Duration::from_millis(self.config.refresh_interval));
+        drop(routes);
 
-        node
+        for bucket_index in stale {
+            let id = self.node_info.id.random_in_bucket(bucket_index, &*self.rng);
+            debug!("Refreshing bucket {} with lookup of {:?}", bucket_index, id);
+            self.lookup_nodes_async(id).then(move |res| {
+                if let Err(e) = res {
+                    warn!("Refresh of bucket {} failed: {}", bucket_index, e);
+                }
+            });
+        }
     }
 
-    fn start_req_handler(self, rx: Receiver<ReqHandle>) {
+    fn start_req_handler(self, rx: Receiver<ReqHandle>) -> JoinHandle<()> {
         thread::spawn(move || {
             for req_handle in rx.iter() {
                 let node = self.clone();
-                thread::spawn(move || {
+                self.execute("incoming request", Box::new(move || {
                     let rep = node.handle_req(req_handle.get_req().clone(),
                                               req_handle.get_src().clone());
                     req_handle.rep(rep);
-                });
+                }));
             }
             info!("Channel closed, since sender is dead.");
-        });
+        })
     }
 
-    fn handle_req(&self, req: Request, src: NodeInfo) -> Reply {
+    /// Updates the routing table with a node that we just heard from
+    ///
+    /// If the node's bucket is full, the newcomer is kept in the bucket's replacement cache and
+    /// the bucket's least recently seen entry is pinged, unless a ping for that bucket is already
+    /// in flight. Only if it fails to answer is it evicted, and the freshest replacement takes its
+    /// place.
+    fn update_routes(&self, node_info: NodeInfo) {
         let mut routes = self.routes.lock().unwrap();
-        routes.update(src);
+        let update = routes.update(node_info);
+        let oldest = match update {
+            Update::Full(oldest) => oldest,
+            Update::Updated => { return; }
+        };
+        let bucket_index = routes.lookup_bucket_index(oldest.id);
         drop(routes);
+
+        if !self.evicting.lock().unwrap().insert(bucket_index) {
+            return;
+        }
+        let evicting = self.evicting.clone();
+        // ping_async takes care of removing oldest if it doesn't respond
+        self.ping_async(oldest).then(move |_| {
+            evicting.lock().unwrap().remove(&bucket_index);
+        });
+    }
+
+    fn handle_req(&self, req: Request, src: NodeInfo) -> Reply {
+        self.update_routes(src);
         match req {
             Request::Ping => {
                 Reply::Ping
             }
-            Request::Store(k, v) => {
+            Request::Store(k, v, ttl) => {
+                let now = self.clock.now();
+                let ttl = ttl.map(|ttl| ttl.min(MAX_TTL));
+                let record = Record {
+                    value: v,
+                    stored_at: now,
+                    expires: ttl.map(|ttl| now + Duration::from_millis(ttl)),
+                };
                 let mut store = self.store.lock().unwrap();
-                store.insert(k, v);
+                let exists = match store.get(&k) {
+                    Ok(record) => record.is_some(),
+                    Err(e) => {
+                        warn!("Failed to read record from the store: {}", e);
+                        false
+                    }
+                };
+                if store.len() < self.config.max_values || exists {
+                    if let Err(e) = store.put(k, record) {
+                        warn!("Failed to store value: {}", e);
+                    }
+                } else {
+                    warn!("Store is full, ignoring value for {}", String::from_utf8_lossy(&k));
+                }
 
                 Reply::Ping
             }
             Request::FindNode(id) => {
                 let routes = self.routes.lock().unwrap();
 
-                Reply::FindNode(routes.closest_nodes(id, K_PARAM))
+                Reply::FindNode(routes.closest_nodes(id, self.config.k_param))
             }
             Request::FindValue(k) => {
-                let hash = Key::hash(k.clone());
+                let hash = Key::hash(&k);
 
+                let now = self.clock.now();
                 let mut store = self.store.lock().unwrap();
-                let lookup_res = store.remove(&k);
+                let lookup_res = match store.get(&k) {
+                    Ok(record) => {
+                        record.filter(|record| !record.is_expired(now))
+                              .map(|record| (record.value, record.expires))
+                    }
+                    Err(e) => {
+                        warn!("Failed to read record from the store: {}", e);
+                        None
+                    }
+                };
                 drop(store);
 
                 match lookup_res {
-                    Some(v) => {
-                        Reply::FindValue(FindValueResult::Value(v))
+                    Some((v, expires)) => {
+                        Reply::FindValue(FindValueResult::Value(v, remaining_ttl(expires, now)))
                     }
                     None => {
                         let routes = self.routes.lock().unwrap();
-                        Reply::FindValue(FindValueResult::Nodes(routes.closest_nodes(hash, K_PARAM)))
+                        Reply::FindValue(FindValueResult::Nodes(routes.closest_nodes(hash, self.config.k_param)))
                     }
                 }
             }
         }
     }
 
-    pub fn ping_raw(&self, dst: NodeInfo) -> Receiver<Option<Reply>> {
+    pub fn ping_raw(&self, dst: NodeInfo) -> Promise<Reply> {
         self.rpc.send_req(Request::Ping, dst)
     }
 
-    pub fn store_raw(&self, dst: NodeInfo, k: String, v: String) -> Receiver<Option<Reply>> {
-        self.rpc.send_req(Request::Store(k, v), dst)
+    pub fn store_raw(&self, dst: NodeInfo, k: Vec<u8>, v: Vec<u8>, ttl: Option<u64>) -> Promise<Reply> {
+        self.rpc.send_req(Request::Store(k, v, ttl), dst)
     }
 
-    pub fn find_node_raw(&self, dst: NodeInfo, id: Key) -> Receiver<Option<Reply>> {
+    pub fn find_node_raw(&self, dst: NodeInfo, id: Key) -> Promise<Reply> {
         self.rpc.send_req(Request::FindNode(id), dst)
     }
 
-    pub fn find_value_raw(&self, dst: NodeInfo, k: String) -> Receiver<Option<Reply>> {
+    pub fn find_value_raw(&self, dst: NodeInfo, k: Vec<u8>) -> Promise<Reply> {
         self.rpc.send_req(Request::FindValue(k), dst)
     }
 
-    pub fn ping(&self, dst: NodeInfo) -> Option<()> {
-        let rep = self.ping_raw(dst.clone()).recv().unwrap(); // err: pending reply channel closed
-        let mut routes = self.routes.lock().unwrap();
-        if let Some(Reply::Ping) = rep {
-            routes.update(dst);
-            Some(())
-        } else {
-            routes.remove(&dst);
-            None
-        }
+    /// Maps the reply from dst through f, updating the routing table depending on whether dst
+    /// answered
+    ///
+    /// Only a timeout evicts dst; errors on this side, such as a message too long to send, say
+    /// nothing about dst.
+    fn on_rep<T, F>(&self, dst: NodeInfo, rep: Promise<Reply>, f: F) -> Promise<T>
+        where T: Send + 'static, F: FnOnce(Reply) -> Result<T> + Send + 'static {
+        let (promise, resolver) = Promise::pair();
+        let node = self.worker();
+        rep.then(move |rep| {
+            match rep {
+                Ok(rep) => {
+                    node.update_routes(dst);
+                    resolver.resolve(f(rep));
+                }
+                Err(Error::Timeout) => {
+                    node.routes.lock().unwrap().remove(&dst);
+                    resolver.resolve(Err(Error::Timeout));
+                }
+                Err(e) => {
+                    resolver.resolve(Err(e));
+                }
+            }
+        });
+        promise
     }
 
-    pub fn store(&self, dst: NodeInfo, k: String, v: String) -> Option<()> {
-        let rep = self.store_raw(dst.clone(), k, v).recv().unwrap(); // err: pending reply channel closed
-        let mut routes = self.routes.lock().unwrap();
-        if let Some(Reply::Ping) = rep {
-            routes.update(dst);
-            Some(())
-        } else {
-            routes.remove(&dst);
-            None
-        }
+    pub fn ping_async(&self, dst: NodeInfo) -> Promise<()> {
+        self.on_rep(dst.clone(), self.ping_raw(dst), |rep| {
+            match rep {
+                Reply::Ping => Ok(()),
+                _ => Err(Error::UnexpectedReply),
+            }
+        })
     }
 
-    pub fn find_node(&self, dst: NodeInfo, id: Key) -> Option<Vec<NodeAndDistance>> {
-        let rep = self.find_node_raw(dst.clone(), id).recv().unwrap(); // err: pending reply channel closed
-        let mut routes = self.routes.lock().unwrap();
-        if let Some(Reply::FindNode(entries)) = rep {
-            routes.update(dst);
-            Some(entries)
-        } else {
-            routes.remove(&dst);
-            None
-        }
+    pub fn store_async(&self, dst: NodeInfo, k: Vec<u8>, v: Vec<u8>, ttl: Option<u64>) -> Promise<()> {
+        self.on_rep(dst.clone(), self.store_raw(dst, k, v, ttl), |rep| {
+            match rep {
+                Reply::Ping => Ok(()),
+                _ => Err(Error::UnexpectedReply),
+            }
+        })
     }
 
-    pub fn find_value(&self, dst: NodeInfo, k: String) -> Option<FindValueResult> {
-        let rep = self.find_value_raw(dst.clone(), k).recv().unwrap(); // err: pending reply channel closed
-        let mut routes = self.routes.lock().unwrap();
-        if let Some(Reply::FindValue(res)) = rep {
-            routes.update(dst);
-            Some(res)
-        } else {
-            routes.remove(&dst);
-            None
-        }
+    pub fn find_node_async(&self, dst: NodeInfo, id: Key) -> Promise<Vec<NodeAndDistance>> {
+        self.on_rep(dst.clone(), self.find_node_raw(dst, id), |rep| {
+            match rep {
+                Reply::FindNode(entries) => Ok(entries),
+                _ => Err(Error::UnexpectedReply),
+            }
+        })
     }
 
-    pub fn lookup_nodes(&self, id: Key) -> Vec<NodeAndDistance> {
-        let mut queried = HashSet::new();
-        let mut ret = HashSet::new();
+    pub fn find_value_async(&self, dst: NodeInfo, k: Vec<u8>) -> Promise<FindValueResult> {
+        self.on_rep(dst.clone(), self.find_value_raw(dst, k), |rep| {
+            match rep {
+                Reply::FindValue(res) => Ok(res),
+                _ => Err(Error::UnexpectedReply),
+            }
+        })
+    }
 
-        // Add the closest nodes we know to our queue of nodes to query
-        let routes = self.routes.lock().unwrap();
-        let mut to_query = BinaryHeap::from(routes.closest_nodes(id, K_PARAM));
-        drop(routes);
+    pub fn lookup_nodes_async(&self, id: Key) -> Promise<Vec<NodeAndDistance>> {
+        self.start_lookup_nodes(id, false).map(|res| res.map(|res| res.closest))
+    }
 
-        for entry in &to_query {
-            queried.insert(entry.clone());
-        }
+    pub fn lookup_value_async(&self, k: Vec<u8>) -> Promise<(Option<Vec<u8>>, Vec<NodeAndDistance>)> {
+        self.start_lookup_value(k, 1, false).map(|res| {
+            res.map(|res| (res.values.into_iter().next().map(|(_, v, _)| v), res.closest))
+        })
+    }
+
+    pub fn put_async(&self, k: Vec<u8>, v: Vec<u8>) -> Promise<usize> {
+        self.publish(k, v, None, 1)
+    }
 
-        while !to_query.is_empty() {
-            let mut joins = Vec::new();
-            let mut queries = Vec::new();
-            let mut results = Vec::new();
-            for _ in 0..A_PARAM {
-                match to_query.pop() {
-                    Some(entry) => { queries.push(entry); }
-                    None => { break; }
+    pub fn get_async(&self, k: Vec<u8>) -> Promise<Option<Vec<u8>>> {
+        let node = self.worker();
+        self.start_lookup_value(k.clone(), 1, false).map(move |res| {
+            let res = try!(res);
+            let (holder, v, remaining) = match res.values.into_iter().next() {
+                Some((NodeAndDistance(holder, _), v, remaining)) => (holder, v, remaining),
+                None => { return Ok(None); }
+            };
+            // Cache the value on the closest node that responded without it. The cached copy
+            // expires sooner the more nodes there are between it and the key, so that caches far
+            // from the key don't outlive the value, and never after the holder's copy does.
+            let target = res.closest.into_iter().enumerate()
+                .find(|&(_, NodeAndDistance(ref node_info, _))| node_info.id != holder.id);
+            if let Some((closer, NodeAndDistance(node_info, _))) = target {
+                let ttl = node.config.cache_ttl.checked_shr(closer as u32).unwrap_or(0);
+                let ttl = remaining.map_or(ttl, |remaining| ttl.min(remaining));
+                if ttl == 0 {
+                    return Ok(Some(v));
                 }
+                debug!("Caching {} on {:?} for {}ms", String::from_utf8_lossy(&k), node_info.id,
+                       ttl);
+                let id = node_info.id;
+                node.store_async(node_info, k, v.clone(), Some(ttl)).then(move |res| {
+                    if let Err(e) = res {
+                        warn!("Failed to cache value on {:?}: {}", id, e);
+                    }
+                });
             }
-            for &NodeAndDistance(ref ni, _) in &queries {
-                let ni = ni.clone();
-                let node = self.clone();
-                joins.push(thread::spawn(move || {
-                    node.find_node(ni.clone(), id)
-                }));
-            }
-            for j in joins {
-                results.push(j.join().unwrap());
+            Ok(Some(v))
+        })
+    }
+
+    pub fn get_quorum_async(&self, k: Vec<u8>, replicas: usize) -> Promise<QuorumValue> {
+        let node = self.worker();
+        self.start_lookup_value(k.clone(), replicas, false).map(move |res| {
+            let mut values = try!(res).values;
+            values.sort_by(|a, b| (a.0).1.cmp(&(b.0).1));
+
+            let mut counts = HashMap::new();
+            for &(_, ref v, _) in &values {
+                *counts.entry(v.clone()).or_insert(0) += 1;
             }
-            for (res, query) in results.into_iter().zip(queries) {
-                if let Some(entries) = res {
-                    ret.insert(query);
-                    for entry in entries {
-                        if queried.insert(entry.clone()) {
-                            to_query.push(entry);
-                        }
+            // max_by_key picks the last of equal elements, so iterating in reverse breaks ties in
+            // favour of the closest replica
+            let value = values.iter().rev()
+                .max_by_key(|&&(_, ref v, _)| counts[v])
+                .map(|&(_, ref v, _)| v.clone());
+
+            let mut dissenters = Vec::new();
+            if let Some(ref value) = value {
+                // Without a strict majority there's no telling which value is right, so nothing
+                // is repaired. Otherwise the repair gets the TTL the closest majority replica has
+                // left, so a value put with a TTL still expires.
+                let repair_ttl = if 2 * counts[value] > values.len() {
+                    values.iter().find(|&&(_, ref v, _)| v == value).map(|&(_, _, ttl)| ttl)
+                } else {
+                    None
+                };
+                for &(NodeAndDistance(ref node_info, _), ref v, _) in &values {
+                    if v == value {
+                        continue;
+                    }
+                    warn!("Replica {:?} has a different value for {}", node_info.id,
+                          String::from_utf8_lossy(&k));
+                    if let Some(ttl) = repair_ttl {
+                        let id = node_info.id;
+                        let repair = node.store_async(node_info.clone(), k.clone(), value.clone(),
+                                                      ttl);
+                        repair.then(move |res| {
+                            if let Err(e) = res {
+                                warn!("Failed to repair replica {:?}: {}", id, e);
+                            }
+                        });
                     }
+                    dissenters.push((node_info.clone(), v.clone()));
                 }
             }
-        }
+            Ok(QuorumValue {
+                value: value,
+                replicas: values.len(),
+                dissenters: dissenters,
+            })
+        })
+    }
 
-        let mut ret = ret.into_iter().collect::<Vec<_>>();
-        ret.sort_by(|a,b| a.1.cmp(&b.1));
-        ret.truncate(K_PARAM);
-        ret
+    pub fn ping(&self, dst: NodeInfo) -> Result<()> {
+        self.ping_async(dst).wait()
     }
 
-    pub fn lookup_value(&self, k: String) -> (Option<String>, Vec<NodeAndDistance>) {
-        let id = Key::hash(k.clone());
-        let mut queried = HashSet::new();
-        let mut ret = HashSet::new();
+    pub fn store(&self, dst: NodeInfo, k: Vec<u8>, v: Vec<u8>, ttl: Option<u64>) -> Result<()> {
+        self.store_async(dst, k, v, ttl).wait()
+    }
 
-        // Add the closest nodes we know to our queue of nodes to query
-        let routes = self.routes.lock().unwrap();
-        let mut to_query = BinaryHeap::from(routes.closest_nodes(id, K_PARAM));
+    pub fn find_node(&self, dst: NodeInfo, id: Key) -> Result<Vec<NodeAndDistance>> {
+        self.find_node_async(dst, id).wait()
+    }
+
+    pub fn find_value(&self, dst: NodeInfo, k: Vec<u8>) -> Result<FindValueResult> {
+        self.find_value_async(dst, k).wait()
+    }
+
+    /// Starts a lookup of the nodes closest to id, sending requests with query
+    fn start_lookup(&self, id: Key, values: usize, query: Query, trace: bool)
+        -> Promise<LookupResult> {
+        let mut routes = self.routes.lock().unwrap();
+        routes.touch(id, self.clock.now());
+        let initial = routes.closest_nodes(id, self.config.k_param);
         drop(routes);
+        Lookup::start(initial, self.config.k_param, self.config.a_param, values, query,
+                      self.clock.clone(), trace)
+    }
 
-        for entry in &to_query {
-            queried.insert(entry.clone());
-        }
+    fn start_lookup_nodes(&self, id: Key, trace: bool) -> Promise<LookupResult> {
+        let node = self.worker();
+        let query: Query = Arc::new(move |dst| {
+            node.find_node_async(dst, id).map(|res| res.map(FindValueResult::Nodes))
+        });
+        self.start_lookup(id, 1, query, trace)
+    }
 
-        while !to_query.is_empty() {
-            let mut joins = Vec::new();
-            let mut queries = Vec::new();
-            let mut results = Vec::new();
-            for _ in 0..A_PARAM {
-                match to_query.pop() {
-                    Some(entry) => { queries.push(entry); }
-                    None => { break; }
-                }
-            }
-            for &NodeAndDistance(ref ni, _) in &queries {
-                let k = k.clone();
-                let ni = ni.clone();
-                let node = self.clone();
-                joins.push(thread::spawn(move || {
-                    node.find_value(ni.clone(), k)
-                }));
-            }
-            for j in joins {
-                results.push(j.join().unwrap());
+    /// Starts a lookup of k, ending early once values nodes have returned it
+    fn start_lookup_value(&self, k: Vec<u8>, values: usize, trace: bool) -> Promise<LookupResult> {
+        let node = self.worker();
+        let id = Key::hash(&k);
+        let query: Query = Arc::new(move |dst| node.find_value_async(dst, k.clone()));
+        self.start_lookup(id, values, query, trace)
+    }
+
+    pub fn lookup_nodes(&self, id: Key) -> Result<Vec<NodeAndDistance>> {
+        self.lookup_nodes_async(id).wait()
+    }
+
+    pub fn lookup_value(&self, k: Vec<u8>) -> Result<(Option<Vec<u8>>, Vec<NodeAndDistance>)> {
+        self.lookup_value_async(k).wait()
+    }
+
+    /// Like lookup_nodes, also returning a record of every request the lookup sent
+    pub fn lookup_nodes_traced(&self, id: Key) -> Result<(Vec<NodeAndDistance>, LookupTrace)> {
+        let res = try!(self.start_lookup_nodes(id, true).wait());
+        Ok((res.closest, res.trace.unwrap()))
+    }
+
+    /// Like lookup_value, also returning a record of every request the lookup sent
+    pub fn lookup_value_traced(&self, k: Vec<u8>)
+        -> Result<(Option<Vec<u8>>, Vec<NodeAndDistance>, LookupTrace)> {
+        let res = try!(self.start_lookup_value(k, 1, true).wait());
+        let value = res.values.into_iter().next().map(|(_, v, _)| v);
+        Ok((value, res.closest, res.trace.unwrap()))
+    }
+
+    /// Stores v at k on the closest nodes, returning how many of them acknowledged it
+    ///
+    /// Fails with Error::QuorumNotMet if no node did.
+    pub fn put(&self, k: Vec<u8>, v: Vec<u8>) -> Result<usize> {
+        self.put_with_ttl(k, v, None)
+    }
+
+    /// Stores v at k on the closest nodes, to expire after ttl milliseconds (at most 100 years)
+    ///
+    /// This node remembers the value, and publishes it again every original_republish_interval
+    /// until it expires.
+    pub fn put_with_ttl(&self, k: Vec<u8>, v: Vec<u8>, ttl: Option<u64>) -> Result<usize> {
+        self.put_quorum(k, v, ttl, 1)
+    }
+
+    /// Like put_with_ttl, failing with Error::QuorumNotMet unless at least min_acks of the closest
+    /// nodes acknowledged the value
+    ///
+    /// The value is still remembered and republished when the quorum isn't met.
+    pub fn put_quorum(&self, k: Vec<u8>, v: Vec<u8>, ttl: Option<u64>, min_acks: usize)
+        -> Result<usize> {
+        self.publish(k, v, ttl, min_acks).wait()
+    }
+
+    /// Remembers v as published by this node, and replicates it on at least min_acks nodes
+    fn publish(&self, k: Vec<u8>, v: Vec<u8>, ttl: Option<u64>, min_acks: usize) -> Promise<usize> {
+        let now = self.clock.now();
+        let ttl = ttl.map(|ttl| ttl.min(MAX_TTL));
+        let publication = Publication {
+            value: v.clone(),
+            published_at: now,
+            expires: ttl.map(|ttl| now + Duration::from_millis(ttl)),
+        };
+        let mut published = self.published.lock().unwrap();
+        published.insert(k.clone(), publication);
+        drop(published);
+
+        self.replicate(k, v, ttl).map(move |res| {
+            let acks = try!(res);
+            if acks < min_acks {
+                return Err(Error::QuorumNotMet(acks, min_acks));
             }
-            for (res, query) in results.into_iter().zip(queries) {
-                if let Some(fvres) = res {
-                    match fvres {
-                        FindValueResult::Nodes(entries) => {
-                            ret.insert(query);
-                            for entry in entries {
-                                if queried.insert(entry.clone()) {
-                                    to_query.push(entry);
-                                }
+            Ok(acks)
+        })
+    }
+
+    /// Stores v at k on the k_param nodes closest to k
+    ///
+    /// The returned Promise completes with the number of nodes that acknowledged the value, once
+    /// they have all replied or failed. If no node acknowledged it because the value couldn't be
+    /// sent at all (it's too long, or the node has shut down), it fails with that error instead.
+    fn replicate(&self, k: Vec<u8>, v: Vec<u8>, ttl: Option<u64>) -> Promise<usize> {
+        let (promise, resolver) = Promise::pair();
+        let node = self.worker();
+        self.lookup_nodes_async(Key::hash(&k)).then(move |res| {
+            let candidates = match res {
+                Ok(candidates) => candidates,
+                Err(e) => {
+                    resolver.resolve(Err(e));
+                    return;
+                }
+            };
+            let ids = candidates.iter().map(|entry| entry.0.id).collect::<Vec<_>>();
+            let stores = candidates.into_iter().map(|NodeAndDistance(node_info, _)| {
+                node.store_async(node_info, k.clone(), v.clone(), ttl)
+            }).collect();
+            Promise::all(stores).then(move |res| {
+                resolver.resolve(res.and_then(|results| {
+                    let mut acks = 0;
+                    let mut send_error = None;
+                    for (id, res) in ids.into_iter().zip(results) {
+                        match res {
+                            Ok(()) => { acks += 1; }
+                            Err(e @ Error::Encode(_)) | Err(e @ Error::Shutdown) => {
+                                send_error = Some(e);
                             }
-                        }
-                        FindValueResult::Value(val) => {
-                            let mut ret = ret.into_iter().collect::<Vec<_>>();
-                            ret.sort_by(|a,b| a.1.cmp(&b.1));
-                            ret.truncate(K_PARAM);
-                            return (Some(val), ret);
+                            Err(e) => { warn!("Failed to store value on {:?}: {}", id, e); }
                         }
                     }
-                }
-            }
-        }
+                    match send_error {
+                        Some(e) if acks == 0 => Err(e),
+                        _ => Ok(acks),
+                    }
+                }));
+            });
+        });
+        promise
+    }
 
-        let mut ret = ret.into_iter().collect::<Vec<_>>();
-        ret.sort_by(|a,b| a.1.cmp(&b.1));
-        ret.truncate(K_PARAM);
+    pub fn get(&self, k: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.get_async(k).wait()
+    }
 
-        (None, ret)
+    /// Looks up k on up to replicas of the closest nodes holding it, returning the value most of
+    /// them agree on
+    ///
+    /// If more than half of the replicas agree, the others are sent the majority value, with the
+    /// TTL it has left.
+    pub fn get_quorum(&self, k: Vec<u8>, replicas: usize) -> Result<QuorumValue> {
+        self.get_quorum_async(k, replicas).wait()
     }
 
-    pub fn put(&self, k: String, v: String) {
-        let candidates = self.lookup_nodes(Key::hash(k.clone()));
-        for NodeAndDistance(node_info, _) in candidates {
-            let node = self.clone();
-            let k = k.clone();
-            let v = v.clone();
-            thread::spawn(move || {
-                node.store(node_info, k, v).unwrap();
-            });
-        }
+    /// Stores the string v at the string k, like put
+    pub fn put_str(&self, k: &str, v: &str) -> Result<usize> {
+        self.put(k.as_bytes().to_vec(), v.as_bytes().to_vec())
     }
 
-    pub fn get(&self, k: String) -> Option<String> {
-        let (v_opt, mut nodes) = self.lookup_value(k.clone());
-        v_opt.map(|v| {
-            if let Some(NodeAndDistance(store_target, _)) = nodes.pop() {
-                self.store(store_target, k, v.clone());
-            } else {
-                self.store(self.node_info.clone(), k, v.clone());
+    /// Looks up the string value at the string k, like get. Fails with Error::Decode if the value
+    /// isn't valid UTF-8.
+    pub fn get_str(&self, k: &str) -> Result<Option<String>> {
+        match try!(self.get(k.as_bytes().to_vec())) {
+            Some(v) => {
+                let v = try!(String::from_utf8(v).map_err(|e| {
+                    Error::Decode(format!("value is not valid UTF-8: {}", e))
+                }));
+                Ok(Some(v))
             }
-            v
-        })
+            None => Ok(None),
+        }
     }
 
     pub fn print_routes(&self) {
@@ -333,3 +865,294 @@ impl Kademlia {
         routes.print();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::*;
+    use ::clock::VirtualClock;
+    use ::lookup::QueryOutcome;
+    use ::sim::SimNetwork;
+
+    fn start_node(net: &SimNetwork, clock: &Arc<VirtualClock>, config: Config) -> Kademlia {
+        Kademlia::start_with_transport(String::from("test_net"), Key::hash(b"node"), net.bind(),
+                                       clock.clone(), Arc::new(net.rng()),
+                                       Box::new(MemoryStore::new()), None, config).unwrap()
+    }
+
+    fn contact(id: Key, addr: String) -> NodeInfo {
+        NodeInfo {
+            id: id,
+            addr: addr,
+            net_id: String::from("test_net"),
+        }
+    }
+
+    #[test]
+    fn full_bucket_pings_its_oldest_entry_once() {
+        let clock = Arc::new(VirtualClock::new());
+        let net = SimNetwork::new(1, clock.clone());
+        let node = start_node(&net, &clock, Config { k_param: 1, ..Config::default() });
+
+        // The oldest entry of bucket 0 is bound but never answers
+        let silent = net.bind();
+        let oldest_id = node.node_info.id.random_in_bucket(0, &*node.rng);
+        let oldest = contact(oldest_id, silent.local_addr().unwrap());
+        node.update_routes(oldest.clone());
+        for i in 0..10 {
+            let id = node.node_info.id.random_in_bucket(0, &*node.rng);
+            node.update_routes(contact(id, format!("nowhere:{}", i)));
+        }
+
+        silent.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        let mut buf = [0u8; 1024];
+        let mut pings = 0;
+        while silent.recv_from(&mut buf).is_ok() {
+            pings += 1;
+        }
+        assert_eq!(pings, 1);
+
+        clock.advance(Duration::from_millis(node.config.timeout));
+        let closest = node.routes.lock().unwrap().closest_nodes(oldest.id, 10);
+        assert!(closest.iter().all(|entry| entry.0.id != oldest.id));
+        assert_eq!(closest.len(), 2);
+        assert!(node.evicting.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn unsendable_put_fails_without_touching_routes() {
+        let clock = Arc::new(VirtualClock::new());
+        let net = SimNetwork::new(1, clock.clone());
+        let first = start_node(&net, &clock, Config::default());
+        let _peers = start_peers(&net, &clock, &first, 15);
+        let routes = |node: &Kademlia| {
+            node.routes.lock().unwrap().closest_nodes(node.node_info.id, 100).len()
+        };
+        let before = routes(&first);
+
+        match first.put(b"key".to_vec(), vec![0; 9000]) {
+            Err(Error::Encode(_)) => {}
+            res => panic!("put returned {:?}", res),
+        }
+        assert_eq!(routes(&first), before);
+    }
+
+    /// Starts count nodes joined to first
+    fn start_peers(net: &SimNetwork, clock: &Arc<VirtualClock>, first: &Kademlia, count: usize)
+        -> Vec<Kademlia> {
+        (0..count).map(|i| {
+            let bootstrap = contact(first.node_info.id, first.node_info.addr.clone());
+            Kademlia::start_with_transport(String::from("test_net"),
+                                           Key::hash(format!("node {}", i).as_bytes()),
+                                           net.bind(), clock.clone(), Arc::new(net.rng()),
+                                           Box::new(MemoryStore::new()), Some(bootstrap),
+                                           Config::default()).unwrap()
+        }).collect()
+    }
+
+    /// Returns the value node holds at k and its expiry, waiting briefly for it to become want
+    fn held(node: &Kademlia, k: &[u8], want: &[u8]) -> Option<(Vec<u8>, Option<Duration>)> {
+        let mut held = None;
+        for _ in 0..100 {
+            held = node.store.lock().unwrap().get(k).unwrap()
+                       .map(|record| (record.value, record.expires));
+            if held.as_ref().map_or(false, |&(ref v, _)| &v[..] == want) {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        held
+    }
+
+    #[test]
+    fn get_quorum_repairs_only_on_strict_majority() {
+        let clock = Arc::new(VirtualClock::new());
+        let net = SimNetwork::new(1, clock.clone());
+        let first = start_node(&net, &clock, Config::default());
+        let peers = start_peers(&net, &clock, &first, 4);
+        let k = b"key".to_vec();
+
+        // One replica against one: no repair
+        first.store(peers[0].node_info.clone(), k.clone(), b"a".to_vec(), None).unwrap();
+        first.store(peers[1].node_info.clone(), k.clone(), b"b".to_vec(), None).unwrap();
+        let res = first.get_quorum(k.clone(), 2).unwrap();
+        assert_eq!(res.replicas, 2);
+        assert_eq!(res.dissenters.len(), 1);
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(held(&peers[0], &k, b"a").unwrap().0, b"a".to_vec());
+        assert_eq!(held(&peers[1], &k, b"b").unwrap().0, b"b".to_vec());
+
+        // Two against one: the odd one out is repaired, keeping the majority's TTL
+        first.store(peers[2].node_info.clone(), k.clone(), b"a".to_vec(), Some(60000)).unwrap();
+        first.store(peers[0].node_info.clone(), k.clone(), b"a".to_vec(), Some(60000)).unwrap();
+        let res = first.get_quorum(k.clone(), 3).unwrap();
+        assert_eq!(res.value, Some(b"a".to_vec()));
+        assert_eq!(res.replicas, 3);
+        let (v, expires) = held(&peers[1], &k, b"a").unwrap();
+        assert_eq!(v, b"a".to_vec());
+        assert_eq!(expires, Some(Duration::from_millis(60000)));
+    }
+
+    #[test]
+    fn huge_ttl_is_capped() {
+        let clock = Arc::new(VirtualClock::new());
+        let net = SimNetwork::new(1, clock.clone());
+        let first = start_node(&net, &clock, Config::default());
+        let peers = start_peers(&net, &clock, &first, 1);
+        let k = b"key".to_vec();
+
+        let peer = peers[0].node_info.clone();
+        first.store(peer, k.clone(), b"v".to_vec(), Some(u64::MAX)).unwrap();
+        let (_, expires) = held(&peers[0], &k, b"v").unwrap();
+        assert_eq!(expires, Some(Duration::from_millis(MAX_TTL)));
+        // The store's lock is still usable
+        assert!(peers[0].store.lock().is_ok());
+    }
+
+    #[test]
+    fn republish_skips_cached_copies_and_leaves_records_alone() {
+        let clock = Arc::new(VirtualClock::new());
+        let net = SimNetwork::new(1, clock.clone());
+        let config = Config { k_param: 1, republish_interval: 1000, ..Config::default() };
+        let node = start_node(&net, &clock, config.clone());
+        // The peer's id is the key of the cached value, so the node isn't responsible for it
+        let cached = b"cached".to_vec();
+        let peer = Kademlia::start_with_transport(String::from("test_net"), Key::hash(&cached),
+                                                  net.bind(), clock.clone(), Arc::new(net.rng()),
+                                                  Box::new(MemoryStore::new()), None,
+                                                  config).unwrap();
+        // With k_param 1 the peer's bootstrap lookup only asks itself, so introduce it
+        node.update_routes(peer.node_info.clone());
+        let owned = (0..).map(|i| format!("owned {}", i).into_bytes()).find(|k| {
+            node.node_info.id.dist(Key::hash(k)) < peer.node_info.id.dist(Key::hash(k))
+        }).unwrap();
+        for k in vec![cached.clone(), owned.clone()] {
+            let record = Record {
+                value: b"v".to_vec(),
+                stored_at: Duration::from_millis(0),
+                expires: None,
+            };
+            node.store.lock().unwrap().put(k, record).unwrap();
+        }
+
+        clock.advance(Duration::from_millis(1000));
+        let republish_thread = node.republish_thread.lock().unwrap().take().unwrap();
+        republish_thread.join().unwrap();
+
+        let republished = node.republished.lock().unwrap().keys().cloned().collect::<Vec<_>>();
+        assert_eq!(republished, vec![owned]);
+        assert!(peer.store.lock().unwrap().get(&cached).unwrap().is_none());
+        let record = node.store.lock().unwrap().get(&cached).unwrap().unwrap();
+        assert_eq!(record.stored_at, Duration::from_millis(0));
+    }
+
+    #[test]
+    fn trace_counts_a_silent_node() {
+        let clock = Arc::new(VirtualClock::new());
+        let net = SimNetwork::new(1, clock.clone());
+        let first = start_node(&net, &clock, Config::default());
+        let peer = start_peers(&net, &clock, &first, 1).remove(0);
+        // Only first knows the silent node, so the peer hears of it in the lookup's second round
+        let silent = net.bind();
+        let silent_id = Key::hash(b"silent");
+        first.update_routes(contact(silent_id, silent.local_addr().unwrap()));
+
+        let lookup = thread::spawn(move || peer.lookup_nodes_traced(Key::hash(b"target")));
+        silent.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut buf = [0u8; 1024];
+        silent.recv_from(&mut buf).unwrap();
+        // The request's timeout is scheduled just after it's sent
+        while !lookup.is_finished() {
+            clock.advance(Duration::from_millis(first.config.timeout));
+            thread::sleep(Duration::from_millis(10));
+        }
+        let (closest, trace) = lookup.join().unwrap().unwrap();
+
+        assert_eq!(closest.len(), 2);
+        assert_eq!(trace.messages, 3);
+        assert_eq!(trace.timeouts, 1);
+        assert_eq!(trace.failures, 0);
+        assert_eq!(trace.hops, 1);
+        assert_eq!(trace.queries.len(), 3);
+        let query = trace.queries.iter().find(|query| query.node.id == silent_id).unwrap();
+        assert_eq!(query.round, 2);
+        assert!(query.latency >= Duration::from_millis(first.config.timeout));
+        match query.outcome {
+            QueryOutcome::Failed(Error::Timeout) => {}
+            ref outcome => panic!("silent node's query ended with {:?}", outcome),
+        }
+    }
+
+    #[test]
+    fn put_quorum_fails_with_too_few_live_nodes() {
+        let clock = Arc::new(VirtualClock::new());
+        let net = SimNetwork::new(1, clock.clone());
+        let first = start_node(&net, &clock, Config::default());
+        let _peers = start_peers(&net, &clock, &first, 2);
+
+        match first.put_quorum(b"key".to_vec(), b"v".to_vec(), None, 4) {
+            Err(Error::QuorumNotMet(3, 4)) => {}
+            res => panic!("put_quorum returned {:?}", res),
+        }
+        // The value is still stored where it could be, and remembered for republishing
+        assert_eq!(first.get(b"key".to_vec()).unwrap(), Some(b"v".to_vec()));
+        assert!(first.published.lock().unwrap().contains_key(&b"key".to_vec()));
+    }
+
+    #[test]
+    fn get_caches_on_closest_node_without_outliving_the_value() {
+        let clock = Arc::new(VirtualClock::new());
+        let net = SimNetwork::new(1, clock.clone());
+        // One query at a time, so the lookup hears from the nodes in order of distance
+        let config = Config { a_param: 1, cache_ttl: 10000, ..Config::default() };
+        let first = start_node(&net, &clock, config);
+        let peers = start_peers(&net, &clock, &first, 2);
+
+        // Keys for which both peers are closer than first; the closer one gets the cached copy
+        let mut keys = (0..).map(|i| format!("key {}", i).into_bytes()).filter(|k| {
+            let dist = |node: &Kademlia| node.node_info.id.dist(Key::hash(k));
+            peers.iter().all(|peer| dist(peer) < dist(&first))
+        });
+        for &ttl in &[30000, 5000] {
+            let k = keys.next().unwrap();
+            let dist = |node: &Kademlia| node.node_info.id.dist(Key::hash(&k));
+            let (cache, holder) = if dist(&peers[0]) < dist(&peers[1]) {
+                (&peers[0], &peers[1])
+            } else {
+                (&peers[1], &peers[0])
+            };
+            first.store(holder.node_info.clone(), k.clone(), b"v".to_vec(), Some(ttl)).unwrap();
+            assert_eq!(first.get(k.clone()).unwrap(), Some(b"v".to_vec()));
+
+            let (_, expires) = held(cache, &k, b"v").unwrap();
+            assert_eq!(expires, Some(Duration::from_millis(ttl.min(10000))));
+            assert!(first.store.lock().unwrap().get(&k).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn restarted_node_reloads_its_records() {
+        let path = env::temp_dir().join(format!("kademlia-restart-{}", process::id()));
+        let _ = fs::remove_file(&path);
+        let config = Config { store_path: Some(path.clone()), ..Config::default() };
+        let start = || {
+            Kademlia::start(String::from("test_net"), Key::hash(b"node"), "127.0.0.1:0", None,
+                            config.clone()).unwrap()
+        };
+
+        let node = start();
+        assert_eq!(node.put(b"key".to_vec(), b"value".to_vec()).unwrap(), 1);
+        node.shutdown();
+        drop(node);
+
+        let node = start();
+        assert_eq!(node.get(b"key".to_vec()).unwrap(), Some(b"value".to_vec()));
+        node.shutdown();
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -1,4 +1,5 @@
 use std::fmt::{Debug,Error,Formatter};
+use std::str::FromStr;
 use crypto::digest::Digest;
 use crypto::sha1::Sha1;
 use rand;
@@ -6,6 +7,8 @@ use rustc_serialize::{Decodable,Decoder,Encodable,Encoder};
 use rustc_serialize::hex::FromHex;
 
 use ::KEY_LEN;
+use ::error;
+use ::rng::Rng;
 
 #[derive(Hash,Ord,PartialOrd,Eq,PartialEq,Copy,Clone)]
 pub struct Key([u8; KEY_LEN]);
@@ -20,17 +23,38 @@ impl Key {
         Key(res)
     }
 
+    /// Returns a random Key drawn from rng
+    pub fn random_from(rng: &dyn Rng) -> Key {
+        let mut res = [0; KEY_LEN];
+        rng.fill(&mut res);
+        Key(res)
+    }
+
+    /// Returns a random Key whose distance from this one has exactly n leading zeroes, i.e. one
+    /// that falls in bucket n of this key's routing table.
+    pub fn random_in_bucket(&self, n: usize, rng: &dyn Rng) -> Key {
+        let mut res = Key::random_from(rng).0;
+        let byte = n / 8;
+        let bit = 0x80u8 >> (n % 8);
+        res[..byte].copy_from_slice(&self.0[..byte]);
+        res[byte] = self.0[byte] ^ (bit | (res[byte] & bit.wrapping_sub(1)));
+        Key(res)
+    }
+
     /// Returns the hashed Key of data.
-    pub fn hash(data: String) -> Key {
+    pub fn hash(data: &[u8]) -> Key {
         let mut hasher = Sha1::new();
-        hasher.input_str(&data);
+        hasher.input(data);
         let mut hash = [0u8; KEY_LEN];
-        for (i, b) in hasher.result_str().as_bytes().iter().take(KEY_LEN).enumerate() {
-            hash[i] = *b;
-        }
+        hasher.result(&mut hash);
         Key(hash)
     }
 
+    /// Returns the raw bytes of the key
+    pub fn as_bytes(&self) -> &[u8; KEY_LEN] {
+        &self.0
+    }
+
     /// XORs two Keys
     pub fn dist(&self, y: Key) -> Distance{
         let mut res = [0; KEY_LEN];
@@ -50,13 +74,25 @@ impl Debug for Key {
     }
 }
 
-impl From<String> for Key {
-    fn from(s: String) -> Key {
-        let mut ret = [0; KEY_LEN];
-        for (i, byte) in s.from_hex().unwrap().iter().enumerate() {
-            ret[i] = *byte;
+impl From<[u8; KEY_LEN]> for Key {
+    fn from(bytes: [u8; KEY_LEN]) -> Key {
+        Key(bytes)
+    }
+}
+
+/// Parses a key from KEY_LEN bytes of hex, as printed by its Debug impl
+impl FromStr for Key {
+    type Err = error::Error;
+
+    fn from_str(s: &str) -> error::Result<Key> {
+        let bytes = try!(s.from_hex().map_err(|e| error::Error::InvalidKey(format!("{}", e))));
+        if bytes.len() != KEY_LEN {
+            return Err(error::Error::InvalidKey(format!("key is {} bytes long, expected {}",
+                                                        bytes.len(), KEY_LEN)));
         }
-        Key(ret)
+        let mut ret = [0; KEY_LEN];
+        ret.copy_from_slice(&bytes);
+        Ok(Key(ret))
     }
 }
 
@@ -90,9 +126,16 @@ impl Encodable for Key {
 pub struct Distance([u8; KEY_LEN]);
 
 impl Distance {
+    /// Returns the raw bytes of the distance
+    pub fn as_bytes(&self) -> &[u8; KEY_LEN] {
+        &self.0
+    }
+
+    /// Returns the number of leading zero bits, i.e. the length of the common prefix of the two
+    /// keys. A zero distance is treated as sharing all but the last bit.
     pub fn zeroes_in_prefix(&self) -> usize {
         for i in 0..KEY_LEN {
-            for j in 8usize..0 {
+            for j in 0usize..8 {
                 if (self.0[i] >> (7 - j)) & 0x1 != 0 {
                     return i * 8 + j;
                 }
@@ -102,6 +145,12 @@ impl Distance {
     }
 }
 
+impl From<[u8; KEY_LEN]> for Distance {
+    fn from(bytes: [u8; KEY_LEN]) -> Distance {
+        Distance(bytes)
+    }
+}
+
 impl Debug for Distance {
     fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
         for x in self.0.iter() {
@@ -136,3 +185,76 @@ impl Encodable for Distance {
         })
     }
 }
+
+/// Counts the leading bits two keys share, one bit at a time
+#[cfg(test)]
+pub(crate) fn common_prefix_len(x: &Key, y: &Key) -> usize {
+    for i in 0..KEY_LEN * 8 {
+        let bit = 0x80u8 >> (i % 8);
+        if x.0[i / 8] & bit != y.0[i / 8] & bit {
+            return i;
+        }
+    }
+    KEY_LEN * 8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::rng::SystemRng;
+
+    /// Keys hashed from a fixed sequence of inputs, so failures can be reproduced
+    fn hashed_keys(n: usize) -> Vec<Key> {
+        (0..n).map(|i| Key::hash(format!("key {}", i).as_bytes())).collect()
+    }
+
+    #[test]
+    fn zeroes_in_prefix_is_common_prefix_len() {
+        let keys = hashed_keys(200);
+        for x in &keys {
+            for y in keys.iter().take(20) {
+                let expected = common_prefix_len(x, y).min(KEY_LEN * 8 - 1);
+                assert_eq!(x.dist(*y).zeroes_in_prefix(), expected, "{:?} and {:?}", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn random_in_bucket_shares_prefix_of_bucket() {
+        for key in hashed_keys(10) {
+            for n in 0..KEY_LEN * 8 {
+                let other = key.random_in_bucket(n, &SystemRng);
+                assert_eq!(common_prefix_len(&key, &other), n, "bucket {} of {:?}", n, key);
+            }
+        }
+    }
+
+    #[test]
+    fn hashed_keys_are_uniform() {
+        // Every byte of the key, split into its high and low nibble, should be spread evenly
+        // over its 16 values. With 16000 keys each count is 1000 on average; a chi-squared
+        // statistic over 15 degrees of freedom stays below 40 unless the hash is biased.
+        let keys = hashed_keys(16000);
+        for byte in 0..KEY_LEN {
+            for &shift in &[0, 4] {
+                let mut counts = [0f64; 16];
+                for key in &keys {
+                    counts[(key.0[byte] >> shift) as usize & 0xf] += 1.0;
+                }
+                let chi2 = counts.iter().map(|&c| (c - 1000.0) * (c - 1000.0) / 1000.0)
+                                 .sum::<f64>();
+                assert!(chi2 < 40.0, "byte {} nibble {} has chi2 {}", byte, shift / 4, chi2);
+            }
+        }
+    }
+
+    #[test]
+    fn parses_its_own_hex() {
+        for key in hashed_keys(10) {
+            assert_eq!(format!("{:?}", key).parse::<Key>().unwrap(), key);
+        }
+        assert!("not hex".parse::<Key>().is_err());
+        assert!("00ff".parse::<Key>().is_err());
+        assert!(format!("{:?}00", Key::hash(b"x")).parse::<Key>().is_err());
+    }
+}
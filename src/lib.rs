@@ -4,24 +4,41 @@ extern crate crypto;
 extern crate rand;
 extern crate rustc_serialize;
 
+mod clock;
+mod codec;
+mod config;
+mod error;
+mod file_store;
 mod kademlia;
 mod key;
+mod lookup;
+mod pool;
+mod promise;
+mod rng;
 mod rpc;
 mod routing;
+mod sim;
+mod store;
+mod transport;
 
-pub use kademlia::Kademlia;
+pub use clock::{Clock,SystemClock,Task,VirtualClock};
+pub use codec::WireFormat;
+pub use config::Config;
+pub use error::{Error,Result};
+pub use file_store::FileStore;
+pub use kademlia::{Kademlia,QuorumValue};
 pub use key::Key;
+pub use lookup::{LookupTrace,QueryOutcome,QueryTrace};
+pub use promise::Promise;
+pub use rng::{Rng,SeededRng,SystemRng};
 pub use routing::NodeInfo;
+pub use sim::{SimNetwork,SimTransport};
+pub use store::{MemoryStore,Record,RecordStore};
+pub use transport::Transport;
 
+/// Wire protocol version, bumped whenever messages or the key space change incompatibly
+const NETWORK_VERSION: u8 = 5;
 /// Length of key in bytes
 const KEY_LEN: usize = 20;
 /// Number of buckets
 const N_BUCKETS: usize = KEY_LEN * 8;
-/// Entries per bucket
-const K_PARAM: usize = 8;
-/// Number of parallel requests
-const A_PARAM: usize = 3;
-/// Max message length
-const MESSAGE_LEN: usize = 8196;
-/// Default timeout
-const TIMEOUT: u64 = 5000;
@@ -0,0 +1,346 @@
+use std::collections::HashSet;
+use std::sync::{Arc,Mutex,MutexGuard};
+use std::time::Duration;
+
+use ::clock::Clock;
+use ::error::{Error,Result};
+use ::kademlia::FindValueResult;
+use ::key::Key;
+use ::promise::{Promise,Resolver};
+use ::routing::{NodeAndDistance,NodeInfo};
+
+/// Sends a FindNode or FindValue request to a node
+pub type Query = Arc<dyn Fn(NodeInfo) -> Promise<FindValueResult> + Send + Sync>;
+
+/// Outcome of a lookup
+pub struct LookupResult {
+    /// The values found, the nodes that returned them, and the TTL they had left there, in the
+    /// order they came in
+    pub values: Vec<(NodeAndDistance, Vec<u8>, Option<u64>)>,
+    /// The closest nodes that responded
+    pub closest: Vec<NodeAndDistance>,
+    /// Record of the lookup, if it was traced
+    pub trace: Option<LookupTrace>,
+}
+
+/// Record of every request sent during a lookup, for diagnosing slow lookups and routing problems
+#[derive(Debug)]
+pub struct LookupTrace {
+    /// Queries in the order their replies (or failures) came in
+    pub queries: Vec<QueryTrace>,
+    /// Number of requests sent
+    pub messages: usize,
+    /// Number of requests that timed out
+    pub timeouts: usize,
+    /// Number of requests that failed for another reason
+    pub failures: usize,
+    /// Highest round of any node that responded
+    pub hops: usize,
+    /// Time from the start of the lookup until it finished
+    pub duration: Duration,
+}
+
+/// A single request sent during a lookup
+#[derive(Debug)]
+pub struct QueryTrace {
+    pub node: NodeInfo,
+    /// 1 for the nodes the lookup started from, n + 1 for the nodes returned by a round n node
+    pub round: usize,
+    /// Time from the start of the lookup until the request was sent
+    pub sent_at: Duration,
+    /// Time from sending the request until its reply or failure
+    pub latency: Duration,
+    pub outcome: QueryOutcome,
+}
+
+#[derive(Debug)]
+pub enum QueryOutcome {
+    /// The node returned these contacts
+    Contacts(Vec<NodeAndDistance>),
+    /// The node returned the value
+    Value(Vec<u8>),
+    Failed(Error),
+}
+
+/// An iterative lookup of the nodes closest to a key
+///
+/// Up to a_param queries are kept in flight at all times, each going to the closest candidate not
+/// yet queried. The lookup ends when the k_param closest candidates have all responded (or, for a
+/// value lookup, as soon as the wanted number of nodes have returned the value). Replies drive the
+/// lookup forward from whichever thread completes them, so no thread is blocked while it runs.
+#[derive(Clone)]
+pub struct Lookup {
+    k_param: usize,
+    a_param: usize,
+    values_wanted: usize,
+    query: Query,
+    clock: Arc<dyn Clock>,
+    started_at: Duration,
+    state: Arc<Mutex<State>>,
+}
+
+struct State {
+    /// Ids of every node that has been a candidate
+    seen: HashSet<Key>,
+    /// Candidates that haven't failed, closest first
+    candidates: Vec<Candidate>,
+    in_flight: usize,
+    values: Vec<(NodeAndDistance, Vec<u8>, Option<u64>)>,
+    trace: Option<LookupTrace>,
+    resolver: Option<Resolver<LookupResult>>,
+}
+
+struct Candidate {
+    entry: NodeAndDistance,
+    round: usize,
+    status: Status,
+}
+
+#[derive(PartialEq,Eq,Clone,Copy)]
+enum Status {
+    Waiting,
+    InFlight,
+    Responded,
+}
+
+impl Lookup {
+    /// Starts a lookup from the given candidates, sending requests with query, and recording a
+    /// LookupTrace if trace is set
+    ///
+    /// The lookup ends early once values_wanted nodes have returned a value.
+    pub fn start(initial: Vec<NodeAndDistance>, k_param: usize, a_param: usize,
+                 values_wanted: usize, query: Query, clock: Arc<dyn Clock>, trace: bool)
+        -> Promise<LookupResult> {
+        let (promise, resolver) = Promise::pair();
+        let trace = if trace {
+            Some(LookupTrace {
+                queries: Vec::new(),
+                messages: 0,
+                timeouts: 0,
+                failures: 0,
+                hops: 0,
+                duration: Duration::from_millis(0),
+            })
+        } else {
+            None
+        };
+        let lookup = Lookup {
+            k_param: k_param,
+            a_param: a_param,
+            values_wanted: values_wanted,
+            query: query,
+            started_at: clock.now(),
+            clock: clock,
+            state: Arc::new(Mutex::new(State {
+                seen: HashSet::new(),
+                candidates: Vec::new(),
+                in_flight: 0,
+                values: Vec::new(),
+                trace: trace,
+                resolver: Some(resolver),
+            })),
+        };
+        let mut state = lookup.state.lock().unwrap();
+        for entry in initial {
+            state.add(entry, 1);
+        }
+        drop(state);
+        lookup.step();
+        promise
+    }
+
+    /// Sends queries until a_param are in flight, or finishes the lookup once the k_param closest
+    /// candidates have all responded
+    fn step(&self) {
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        if state.resolver.is_none() {
+            return;
+        }
+        // Queries still in flight to farther candidates can't change the result, so replies to
+        // them are ignored
+        let k_param = self.k_param;
+        if state.candidates.iter().take(k_param).all(|c| c.status == Status::Responded) {
+            self.finish(guard);
+            return;
+        }
+        let mut to_query = Vec::new();
+        for candidate in state.candidates.iter_mut().take(self.k_param) {
+            if state.in_flight + to_query.len() >= self.a_param {
+                break;
+            }
+            if candidate.status == Status::Waiting {
+                candidate.status = Status::InFlight;
+                to_query.push((candidate.entry.0.clone(), candidate.round));
+            }
+        }
+        state.in_flight += to_query.len();
+        if let Some(ref mut trace) = state.trace {
+            trace.messages += to_query.len();
+        }
+        drop(guard);
+
+        for (node_info, round) in to_query {
+            let lookup = self.clone();
+            let sent_at = self.clock.now();
+            (self.query)(node_info.clone()).then(move |res| {
+                lookup.on_reply(node_info, round, sent_at, res);
+            });
+        }
+    }
+
+    fn on_reply(&self, node_info: NodeInfo, round: usize, sent_at: Duration,
+                res: Result<FindValueResult>) {
+        let id = node_info.id;
+        let mut state = self.state.lock().unwrap();
+        state.in_flight -= 1;
+        if state.resolver.is_none() {
+            // Finished while this request was in flight
+            return;
+        }
+        let outcome = match res {
+            Ok(FindValueResult::Nodes(entries)) => {
+                state.set_status(id, Status::Responded);
+                let contacts = if state.trace.is_some() { entries.clone() } else { Vec::new() };
+                for entry in entries {
+                    state.add(entry, round + 1);
+                }
+                QueryOutcome::Contacts(contacts)
+            }
+            Ok(FindValueResult::Value(v, ttl)) => {
+                if let Some(candidate) = state.set_status(id, Status::Responded) {
+                    state.values.push((candidate, v.clone(), ttl));
+                }
+                QueryOutcome::Value(v)
+            }
+            Err(e) => {
+                state.candidates.retain(|c| c.entry.0.id != id);
+                QueryOutcome::Failed(e)
+            }
+        };
+        if let Some(ref mut trace) = state.trace {
+            match outcome {
+                QueryOutcome::Failed(Error::Timeout) => { trace.timeouts += 1; }
+                QueryOutcome::Failed(_) => { trace.failures += 1; }
+                _ => { trace.hops = trace.hops.max(round); }
+            }
+            trace.queries.push(QueryTrace {
+                node: node_info,
+                round: round,
+                sent_at: sent_at - self.started_at,
+                latency: self.clock.now() - sent_at,
+                outcome: outcome,
+            });
+        }
+        if state.values.len() >= self.values_wanted {
+            self.finish(state);
+            return;
+        }
+        drop(state);
+        self.step();
+    }
+
+    /// Completes the lookup with the closest nodes that responded
+    ///
+    /// The state is unlocked before resolving, since the lookup's callbacks run on the resolving
+    /// thread.
+    fn finish(&self, mut state: MutexGuard<State>) {
+        let resolver = match state.resolver.take() {
+            Some(resolver) => resolver,
+            None => { return; }
+        };
+        let closest = state.closest(self.k_param);
+        let values = state.values.drain(..).collect();
+        let mut trace = state.trace.take();
+        drop(state);
+        if let Some(ref mut trace) = trace {
+            trace.duration = self.clock.now() - self.started_at;
+        }
+        resolver.resolve(Ok(LookupResult {
+            values: values,
+            closest: closest,
+            trace: trace,
+        }));
+    }
+}
+
+impl State {
+    /// Inserts entry among the candidates in order of distance, unless it's been seen before
+    fn add(&mut self, entry: NodeAndDistance, round: usize) {
+        if !self.seen.insert(entry.0.id) {
+            return;
+        }
+        let pos = self.candidates.iter().position(|c| c.entry.1 > entry.1)
+            .unwrap_or(self.candidates.len());
+        self.candidates.insert(pos, Candidate {
+            entry: entry,
+            round: round,
+            status: Status::Waiting,
+        });
+    }
+
+    /// Sets the status of the candidate with the given id, returning its entry
+    fn set_status(&mut self, id: Key, status: Status) -> Option<NodeAndDistance> {
+        self.candidates.iter_mut().find(|c| c.entry.0.id == id).map(|candidate| {
+            candidate.status = status;
+            candidate.entry.clone()
+        })
+    }
+
+    /// Returns the count closest candidates that have responded
+    fn closest(&self, count: usize) -> Vec<NodeAndDistance> {
+        self.candidates.iter()
+            .filter(|c| c.status == Status::Responded)
+            .take(count)
+            .map(|c| c.entry.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::clock::VirtualClock;
+
+    fn entry(i: usize, target: Key) -> NodeAndDistance {
+        let node_info = NodeInfo {
+            id: Key::hash(format!("node {}", i).as_bytes()),
+            addr: format!("sim:{}", i),
+            net_id: String::from("test_net"),
+        };
+        let dist = node_info.id.dist(target);
+        NodeAndDistance(node_info, dist)
+    }
+
+    #[test]
+    fn finishes_once_closest_respond_despite_queries_in_flight() {
+        let target = Key::hash(b"target");
+        let mut entries = (0..3).map(|i| entry(i, target)).collect::<Vec<_>>();
+        entries.sort_by(|x, y| x.1.cmp(&y.1));
+        let (closest, middle, farthest) = (entries[0].clone(), entries[1].0.id, entries[2].0.id);
+
+        // The lookup starts from the two farther nodes; the middle one knows the closest one,
+        // and the farthest never answers
+        let unanswered = Arc::new(Mutex::new(Vec::new()));
+        let held = unanswered.clone();
+        let query: Query = Arc::new(move |node_info: NodeInfo| {
+            if node_info.id == farthest {
+                let (promise, resolver) = Promise::pair();
+                held.lock().unwrap().push(resolver);
+                promise
+            } else if node_info.id == middle {
+                Promise::resolved(Ok(FindValueResult::Nodes(vec![closest.clone()])))
+            } else {
+                Promise::resolved(Ok(FindValueResult::Nodes(Vec::new())))
+            }
+        });
+        let expected = entries.iter().take(2).map(|entry| entry.0.id).collect::<Vec<_>>();
+        let res = Lookup::start(entries[1..].to_vec(), 2, 3, usize::MAX, query,
+                                Arc::new(VirtualClock::new()), false).wait().unwrap();
+        assert_eq!(res.closest.iter().map(|entry| entry.0.id).collect::<Vec<_>>(), expected);
+
+        // A late reply is ignored
+        let resolver = unanswered.lock().unwrap().pop().unwrap();
+        resolver.resolve(Ok(FindValueResult::Nodes(Vec::new())));
+    }
+}
@@ -5,6 +5,17 @@ extern crate env_logger;
 use std::io;
 use kademlia::*;
 
+/// Parses a key typed in by the user, printing why if it isn't one
+fn parse_key(s: &str) -> Option<Key> {
+    match s.parse() {
+        Ok(key) => Some(key),
+        Err(e) => {
+            println!("{}", e);
+            None
+        }
+    }
+}
+
 fn main() {
     env_logger::init().unwrap();
 
@@ -15,8 +26,12 @@ fn main() {
     let bootstrap = if params.len() < 2 {
         None
     } else {
+        let id = match parse_key(params[1]) {
+            Some(id) => id,
+            None => { return; }
+        };
         Some(NodeInfo {
-            id: Key::from(String::from(params[1])),
+            id: id,
             addr: String::from(params[0]),
             net_id: String::from("test_net"),
         })
@@ -24,7 +39,8 @@ fn main() {
     let handle = Kademlia::start(String::from("test_net"),
                                  Key::random(),
                                  "127.0.0.1:0",
-                                 bootstrap);
+                                 bootstrap,
+                                 Config::default()).unwrap();
 
     let mut dummy_info = NodeInfo {
         net_id: String::from("test_net"),
@@ -40,36 +56,64 @@ fn main() {
         let args = buffer.trim_right().split(' ').collect::<Vec<_>>();
         match args[0].as_ref() {
             "p" => {
-                dummy_info.addr = String::from(args[1]);
-                dummy_info.id = Key::from(String::from(args[2]));
-                println!("{:?}", handle.ping(dummy_info.clone()));
+                if let Some(id) = parse_key(args[2]) {
+                    dummy_info.addr = String::from(args[1]);
+                    dummy_info.id = id;
+                    println!("{:?}", handle.ping(dummy_info.clone()));
+                }
             }
             "s" => {
-                dummy_info.addr = String::from(args[1]);
-                dummy_info.id = Key::from(String::from(args[2]));
-                println!("{:?}", handle.store(dummy_info.clone(), String::from(args[3]), String::from(args[4])));
+                if let Some(id) = parse_key(args[2]) {
+                    dummy_info.addr = String::from(args[1]);
+                    dummy_info.id = id;
+                    println!("{:?}", handle.store(dummy_info.clone(), args[3].as_bytes().to_vec(), args[4].as_bytes().to_vec(), None));
+                }
             }
             "fn" => {
-                dummy_info.addr = String::from(args[1]);
-                dummy_info.id = Key::from(String::from(args[2]));
-                println!("{:?}", handle.find_node(dummy_info.clone(), Key::from(String::from(args[3]))));
+                if let (Some(id), Some(target)) = (parse_key(args[2]), parse_key(args[3])) {
+                    dummy_info.addr = String::from(args[1]);
+                    dummy_info.id = id;
+                    println!("{:?}", handle.find_node(dummy_info.clone(), target));
+                }
             }
             "fv" => {
-                dummy_info.addr = String::from(args[1]);
-                dummy_info.id = Key::from(String::from(args[2]));
-                println!("{:?}", handle.find_value(dummy_info.clone(), String::from(args[3])));
+                if let Some(id) = parse_key(args[2]) {
+                    dummy_info.addr = String::from(args[1]);
+                    dummy_info.id = id;
+                    println!("{:?}", handle.find_value(dummy_info.clone(), args[3].as_bytes().to_vec()));
+                }
             }
             "ln" => {
-                println!("{:?}", handle.lookup_nodes(Key::from(String::from(args[1]))));
+                if let Some(id) = parse_key(args[1]) {
+                    println!("{:?}", handle.lookup_nodes(id));
+                }
             }
             "lv" => {
-                println!("{:?}", handle.lookup_value(String::from(args[1])));
+                println!("{:?}", handle.lookup_value(args[1].as_bytes().to_vec()));
+            }
+            "tln" => {
+                if let Some(id) = parse_key(args[1]) {
+                    println!("{:#?}", handle.lookup_nodes_traced(id));
+                }
+            }
+            "tlv" => {
+                println!("{:#?}", handle.lookup_value_traced(args[1].as_bytes().to_vec()));
             }
             "put" => {
-                println!("{:?}", handle.put(String::from(args[1]), String::from(args[2])));
+                println!("{:?}", handle.put_str(args[1], args[2]));
             }
             "get" => {
-                println!("{:?}", handle.get(String::from(args[1])));
+                println!("{:?}", handle.get_str(args[1]));
+            }
+            "qget" => {
+                match args[2].parse() {
+                    Ok(replicas) => {
+                        println!("{:?}", handle.get_quorum(args[1].as_bytes().to_vec(), replicas));
+                    }
+                    Err(e) => {
+                        println!("bad replica count: {}", e);
+                    }
+                }
             }
             _ => {
                 println!("no match");
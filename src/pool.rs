@@ -0,0 +1,71 @@
+use std::result;
+use std::sync::{Arc,Mutex};
+use std::sync::mpsc;
+use std::sync::mpsc::{Receiver,SyncSender,TrySendError};
+use std::thread;
+use std::thread::JoinHandle;
+
+use ::clock::Task;
+
+/// A fixed number of worker threads taking tasks from a bounded queue
+pub struct ThreadPool {
+    tx: Mutex<Option<SyncSender<Task>>>,
+    workers: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl ThreadPool {
+    pub fn new(size: usize, queue_len: usize) -> ThreadPool {
+        let (tx, rx) = mpsc::sync_channel::<Task>(queue_len);
+        let rx = Arc::new(Mutex::new(rx));
+        let workers = (0..size).map(|_| {
+            let rx = rx.clone();
+            thread::spawn(move || {
+                while let Some(task) = next_task(&rx) {
+                    task();
+                }
+            })
+        }).collect();
+        ThreadPool {
+            tx: Mutex::new(Some(tx)),
+            workers: Mutex::new(workers),
+        }
+    }
+
+    /// Queues task to run on a worker, handing it back if the queue is full or the pool has been
+    /// joined
+    pub fn execute(&self, task: Task) -> result::Result<(), Task> {
+        match *self.tx.lock().unwrap() {
+            Some(ref tx) => {
+                tx.try_send(task).map_err(|e| {
+                    match e {
+                        TrySendError::Full(task) | TrySendError::Disconnected(task) => task,
+                    }
+                })
+            }
+            None => Err(task),
+        }
+    }
+
+    /// Stops accepting tasks, and waits for the workers to finish the ones already queued
+    pub fn join(&self) {
+        self.tx.lock().unwrap().take();
+        let workers = self.workers.lock().unwrap().drain(..).collect::<Vec<_>>();
+        for worker in workers {
+            join_thread(worker);
+        }
+    }
+}
+
+/// Waits for thread to finish, unless it is the current thread
+///
+/// A thread can't join itself, which happens when the last handle to a node is dropped on one of
+/// its own threads; that thread finishes on its own once it returns to its loop.
+pub fn join_thread(thread: JoinHandle<()>) {
+    if thread.thread().id() != thread::current().id() {
+        let _ = thread.join();
+    }
+}
+
+fn next_task(rx: &Mutex<Receiver<Task>>) -> Option<Task> {
+    rx.lock().unwrap().recv().ok()
+}
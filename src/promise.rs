@@ -0,0 +1,254 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc,Condvar,Mutex};
+use std::task::{Context,Poll,Waker};
+
+use ::error::{Error,Result};
+
+type Callback<T> = Box<dyn FnOnce(Result<T>) + Send>;
+
+/// The eventual result of an operation
+///
+/// A Promise can be awaited as a Future, blocked on with wait, or given a callback with then.
+pub struct Promise<T> {
+    shared: Arc<Shared<T>>,
+}
+
+/// Completes the Promise it was created with
+///
+/// Dropping a Resolver without calling resolve fails the Promise with Error::ChannelClosed.
+pub struct Resolver<T> {
+    shared: Option<Arc<Shared<T>>>,
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    cond: Condvar,
+}
+
+struct State<T> {
+    result: Option<Result<T>>,
+    waker: Option<Waker>,
+    callback: Option<Callback<T>>,
+}
+
+impl<T> Promise<T> {
+    /// Returns a pending Promise, and the Resolver that completes it
+    pub fn pair() -> (Promise<T>, Resolver<T>) {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                result: None,
+                waker: None,
+                callback: None,
+            }),
+            cond: Condvar::new(),
+        });
+        (Promise { shared: shared.clone() }, Resolver { shared: Some(shared) })
+    }
+
+    /// Returns a Promise that is already complete
+    pub fn resolved(result: Result<T>) -> Promise<T> {
+        let (promise, resolver) = Promise::pair();
+        resolver.resolve(result);
+        promise
+    }
+
+    /// Blocks until the Promise is complete
+    pub fn wait(self) -> Result<T> {
+        let mut state = self.shared.state.lock().unwrap();
+        loop {
+            if let Some(result) = state.result.take() {
+                return result;
+            }
+            state = self.shared.cond.wait(state).unwrap();
+        }
+    }
+
+    /// Calls f with the result once the Promise is complete, right away if it already is
+    ///
+    /// f runs on whichever thread completes the Promise, so it shouldn't block.
+    pub fn then<F>(self, f: F) where F: FnOnce(Result<T>) + Send + 'static {
+        let mut state = self.shared.state.lock().unwrap();
+        match state.result.take() {
+            Some(result) => {
+                drop(state);
+                f(result);
+            }
+            None => {
+                state.callback = Some(Box::new(f));
+            }
+        }
+    }
+
+    /// Returns a Promise of f applied to the result, once this one is complete
+    pub fn map<U, F>(self, f: F) -> Promise<U>
+        where U: Send + 'static, F: FnOnce(Result<T>) -> Result<U> + Send + 'static {
+        let (promise, resolver) = Promise::pair();
+        self.then(move |result| {
+            resolver.resolve(f(result));
+        });
+        promise
+    }
+}
+
+impl<T: Send + 'static> Promise<T> {
+    /// Returns a Promise of the results of promises, in order, once they are all complete
+    pub fn all(promises: Vec<Promise<T>>) -> Promise<Vec<Result<T>>> {
+        if promises.is_empty() {
+            return Promise::resolved(Ok(Vec::new()));
+        }
+        let (promise, resolver) = Promise::pair();
+        let results = Arc::new(Mutex::new(All {
+            results: promises.iter().map(|_| None).collect(),
+            remaining: promises.len(),
+            resolver: Some(resolver),
+        }));
+        for (i, promise) in promises.into_iter().enumerate() {
+            let results = results.clone();
+            promise.then(move |result| {
+                let mut all = results.lock().unwrap();
+                all.results[i] = Some(result);
+                all.remaining -= 1;
+                if all.remaining > 0 {
+                    return;
+                }
+                let results = all.results.drain(..).map(|result| result.unwrap()).collect();
+                let resolver = all.resolver.take().unwrap();
+                drop(all);
+                resolver.resolve(Ok(results));
+            });
+        }
+        promise
+    }
+}
+
+struct All<T> {
+    results: Vec<Option<Result<T>>>,
+    remaining: usize,
+    resolver: Option<Resolver<Vec<Result<T>>>>,
+}
+
+impl<T> Future for Promise<T> {
+    type Output = Result<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<T>> {
+        let mut state = self.shared.state.lock().unwrap();
+        match state.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<T> Resolver<T> {
+    pub fn resolve(mut self, result: Result<T>) {
+        if let Some(shared) = self.shared.take() {
+            complete(&shared, result);
+        }
+    }
+}
+
+impl<T> Drop for Resolver<T> {
+    fn drop(&mut self) {
+        if let Some(shared) = self.shared.take() {
+            complete(&shared, Err(Error::ChannelClosed));
+        }
+    }
+}
+
+fn complete<T>(shared: &Shared<T>, result: Result<T>) {
+    let mut state = shared.state.lock().unwrap();
+    if let Some(callback) = state.callback.take() {
+        drop(state);
+        callback(result);
+        return;
+    }
+    state.result = Some(result);
+    let waker = state.waker.take();
+    drop(state);
+    shared.cond.notify_all();
+    if let Some(waker) = waker {
+        waker.wake();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::task::Wake;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    /// Wakes the thread polling a future
+    struct Unpark(thread::Thread);
+
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    /// Polls future on the current thread until it's ready, returning how many polls it took
+    fn block_on<F: Future + Unpin>(mut future: F) -> (F::Output, usize) {
+        let waker = Waker::from(Arc::new(Unpark(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        let mut polls = 0;
+        loop {
+            polls += 1;
+            if let Poll::Ready(output) = Pin::new(&mut future).poll(&mut cx) {
+                return (output, polls);
+            }
+            thread::park();
+        }
+    }
+
+    #[test]
+    fn promise_can_be_awaited() {
+        let (promise, resolver) = Promise::pair();
+        let resolving = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            resolver.resolve(Ok(5));
+        });
+        let (res, _) = block_on(promise);
+        assert_eq!(res.unwrap(), 5);
+        resolving.join().unwrap();
+
+        let (res, polls) = block_on(Promise::<()>::resolved(Err(Error::Timeout)));
+        assert!(match res { Err(Error::Timeout) => true, _ => false });
+        assert_eq!(polls, 1);
+    }
+
+    #[test]
+    fn dropped_resolver_fails_awaited_promise() {
+        let (promise, resolver) = Promise::<()>::pair();
+        drop(resolver);
+        let (res, _) = block_on(promise);
+        assert!(match res { Err(Error::ChannelClosed) => true, _ => false });
+    }
+
+    #[test]
+    fn then_on_resolved_promise_runs_right_away() {
+        let (tx, rx) = mpsc::channel();
+        Promise::resolved(Ok(5)).then(move |res| {
+            tx.send((res.unwrap(), thread::current().id())).unwrap();
+        });
+        assert_eq!(rx.try_recv().unwrap(), (5, thread::current().id()));
+    }
+
+    #[test]
+    fn then_runs_once_resolved() {
+        let (tx, rx) = mpsc::channel();
+        let (promise, resolver) = Promise::pair();
+        promise.map(|res| res.map(|x: u32| x * 2)).then(move |res| {
+            tx.send(res.unwrap()).unwrap();
+        });
+        assert!(rx.try_recv().is_err());
+        resolver.resolve(Ok(5));
+        assert_eq!(rx.try_recv().unwrap(), 10);
+    }
+}
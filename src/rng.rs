@@ -0,0 +1,64 @@
+use std::sync::Mutex;
+use rand;
+
+/// Source of the random request tokens and refresh targets a node draws
+pub trait Rng: Send + Sync + 'static {
+    /// Returns 64 random bits
+    fn next_u64(&self) -> u64;
+
+    /// Fills buf with random bytes
+    fn fill(&self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(8) {
+            let x = self.next_u64();
+            for (i, byte) in chunk.iter_mut().enumerate() {
+                *byte = (x >> (i * 8)) as u8;
+            }
+        }
+    }
+}
+
+/// An Rng backed by the system rng
+#[derive(Debug,Default)]
+pub struct SystemRng;
+
+impl Rng for SystemRng {
+    fn next_u64(&self) -> u64 {
+        rand::random()
+    }
+}
+
+/// An Rng producing a fixed sequence from a seed, so a simulation can be replayed
+pub struct SeededRng {
+    state: Mutex<SplitMix64>,
+}
+
+impl SeededRng {
+    pub fn new(seed: u64) -> SeededRng {
+        SeededRng {
+            state: Mutex::new(SplitMix64(seed)),
+        }
+    }
+}
+
+impl Rng for SeededRng {
+    fn next_u64(&self) -> u64 {
+        self.state.lock().unwrap().next_u64()
+    }
+}
+
+/// Small deterministic generator, so simulations don't depend on the system rng
+pub struct SplitMix64(pub u64);
+
+impl SplitMix64 {
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
@@ -1,5 +1,6 @@
 use std::cmp::Ordering;
-use ::{N_BUCKETS,K_PARAM};
+use std::time::Duration;
+use ::N_BUCKETS;
 use ::key::{Distance,Key};
 
 #[derive(Hash,Eq,PartialEq,Debug,Clone,RustcEncodable,RustcDecodable)]
@@ -12,7 +13,22 @@ pub struct NodeInfo {
 #[derive(Debug)]
 pub struct RoutingTable {
     node_info: NodeInfo,
-    buckets: Vec<Vec<NodeInfo>>
+    k_param: usize,
+    buckets: Vec<Vec<NodeInfo>>,
+    /// Recently seen nodes that didn't fit in their full bucket, freshest last
+    replacements: Vec<Vec<NodeInfo>>,
+    /// Time of the last lookup that fell into each bucket
+    last_lookup: Vec<Duration>,
+}
+
+/// Outcome of updating the routing table with a node
+#[derive(Debug)]
+pub enum Update {
+    /// The node was added to its bucket, or moved to the tail as the most recently seen entry
+    Updated,
+    /// The node's bucket is full. Contains the bucket's least recently seen entry, which should be
+    /// pinged and removed if it doesn't respond.
+    Full(NodeInfo),
 }
 
 #[derive(Eq,Hash,Clone,Debug,RustcEncodable,RustcDecodable)]
@@ -37,35 +53,54 @@ impl Ord for NodeAndDistance {
 }
 
 impl RoutingTable {
-    pub fn new(node_info: NodeInfo) -> RoutingTable {
+    pub fn new(node_info: NodeInfo, k_param: usize) -> RoutingTable {
         let mut buckets = Vec::new();
+        let mut replacements = Vec::new();
         for _ in 0..N_BUCKETS {
             buckets.push(Vec::new());
+            replacements.push(Vec::new());
         }
         let mut ret = RoutingTable {
             node_info: node_info.clone(),
-            buckets: buckets
+            k_param: k_param,
+            buckets: buckets,
+            replacements: replacements,
+            last_lookup: vec![Duration::from_millis(0); N_BUCKETS],
         };
         ret.update(node_info.clone());
         ret
     }
 
     /// Update the appropriate bucket with the new node's info
-    pub fn update(&mut self, node_info: NodeInfo) {
+    ///
+    /// Buckets are kept in order of last contact, least recently seen first. If the bucket is
+    /// full, the node goes into the bucket's replacement cache instead; the caller decides whether
+    /// to evict the returned entry.
+    pub fn update(&mut self, node_info: NodeInfo) -> Update {
         let bucket_index = self.lookup_bucket_index(node_info.id);
         let bucket = &mut self.buckets[bucket_index];
         let node_index = bucket.iter().position(|x| x.id == node_info.id);
         match node_index {
             Some(i) => {
-                let temp = bucket.remove(i);
-                bucket.push(temp);
+                bucket.remove(i);
+                bucket.push(node_info);
+                Update::Updated
             }
             None => {
-                if bucket.len() < K_PARAM {
+                if bucket.len() < self.k_param {
                     bucket.push(node_info);
+                    Update::Updated
                 } else {
-                    // go through bucket, pinging nodes, replace one
-                    // that doesn't respond.
+                    let oldest = bucket[0].clone();
+                    let cache = &mut self.replacements[bucket_index];
+                    if let Some(i) = cache.iter().position(|x| x.id == node_info.id) {
+                        cache.remove(i);
+                    }
+                    cache.push(node_info);
+                    if cache.len() > self.k_param {
+                        cache.remove(0);
+                    }
+                    Update::Full(oldest)
                 }
             }
         }
@@ -73,33 +108,62 @@ impl RoutingTable {
 
     /// Lookup the nodes closest to item in this table
     ///
-    /// NOTE: This method is a really stupid, linear time search. I can't find
-    /// info on how to use the buckets effectively to solve this.
+    /// If item falls in bucket i, every node in bucket i is closer to item than any node in a
+    /// deeper bucket, and those are all closer than any node in a shallower bucket, with each
+    /// shallower bucket farther than the last. So buckets are visited in that order, and the
+    /// search stops as soon as count nodes have been found.
     pub fn closest_nodes(&self, item: Key, count: usize) -> Vec<NodeAndDistance> {
         if count == 0 {
             return Vec::new();
         }
+        let index = self.lookup_bucket_index(item);
         let mut ret = Vec::with_capacity(count);
-        for bucket in &self.buckets {
-            for node_info in bucket {
-                ret.push( NodeAndDistance(node_info.clone(), node_info.id.dist(item)) );
+        push_sorted(&mut ret, item, &self.buckets[index..index + 1]);
+        if ret.len() < count {
+            push_sorted(&mut ret, item, &self.buckets[index + 1..]);
+        }
+        for i in (0..index).rev() {
+            if ret.len() >= count {
+                break;
             }
+            push_sorted(&mut ret, item, &self.buckets[i..i + 1]);
         }
-        ret.sort_by(|a,b| a.1.cmp(&b.1));
         ret.truncate(count);
         ret
     }
 
+    /// Removes a node, promoting the freshest entry of the bucket's replacement cache in its place
     pub fn remove(&mut self, node_info: &NodeInfo) {
         let bucket_index = self.lookup_bucket_index(node_info.id);
+        let cache = &mut self.replacements[bucket_index];
+        if let Some(item_index) = cache.iter().position(|x| x == node_info) {
+            cache.remove(item_index);
+        }
         if let Some(item_index) = self.buckets[bucket_index].iter().position(|x| x == node_info) {
             self.buckets[bucket_index].remove(item_index);
+            if let Some(replacement) = cache.pop() {
+                self.buckets[bucket_index].push(replacement);
+            }
         } else {
             warn!("Tried to remove routing entry that doesn't exist.");
         }
     }
 
-    fn lookup_bucket_index(&self, item: Key) -> usize {
+    /// Records that a lookup for item happened at time now
+    pub fn touch(&mut self, item: Key, now: Duration) {
+        let bucket_index = self.lookup_bucket_index(item);
+        self.last_lookup[bucket_index] = now;
+    }
+
+    /// Returns the indices of non-empty buckets that haven't seen a lookup within interval
+    pub fn stale_buckets(&self, now: Duration, interval: Duration) -> Vec<usize> {
+        (0..N_BUCKETS).filter(|&i| {
+            !self.buckets[i].is_empty() && now >= self.last_lookup[i] + interval
+        }).collect()
+    }
+
+    /// Returns the index of the bucket item falls in
+    pub fn lookup_bucket_index(&self, item: Key) -> usize {
         self.node_info.id.dist(item).zeroes_in_prefix()
     }
 
@@ -107,3 +171,134 @@ impl RoutingTable {
         info!("{:?}", self.buckets);
     }
 }
+
+/// Appends the nodes in buckets to ret, sorted by their distance to item
+fn push_sorted(ret: &mut Vec<NodeAndDistance>, item: Key, buckets: &[Vec<NodeInfo>]) {
+    let start = ret.len();
+    for bucket in buckets {
+        for node_info in bucket {
+            ret.push( NodeAndDistance(node_info.clone(), node_info.id.dist(item)) );
+        }
+    }
+    ret[start..].sort_by(|a,b| a.1.cmp(&b.1));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+    use ::key::common_prefix_len;
+
+    fn node(i: usize) -> NodeInfo {
+        NodeInfo {
+            id: Key::hash(format!("node {}", i).as_bytes()),
+            addr: format!("sim:{}", i),
+            net_id: String::from("test_net"),
+        }
+    }
+
+    #[test]
+    fn bucket_index_is_common_prefix_len() {
+        let own = node(0);
+        let mut table = RoutingTable::new(own.clone(), 1000);
+        for i in 1..2000 {
+            let other = node(i);
+            let index = table.lookup_bucket_index(other.id);
+            assert_eq!(index, common_prefix_len(&own.id, &other.id));
+            table.update(other.clone());
+            assert!(table.buckets[index].contains(&other));
+        }
+        // The node itself goes in the last bucket, which nothing else can share
+        assert_eq!(table.lookup_bucket_index(own.id), N_BUCKETS - 1);
+    }
+
+    #[test]
+    fn hashed_keys_fill_buckets_by_halves() {
+        // A uniformly distributed key falls in bucket i with probability 2^-(i + 1), so of 16384
+        // keys about 8192 land in bucket 0, 4096 in bucket 1, and so on
+        let table = RoutingTable::new(node(0), 8);
+        let mut counts = vec![0usize; N_BUCKETS];
+        let n = 1 << 14;
+        for i in 0..n {
+            counts[table.lookup_bucket_index(Key::hash(format!("key {}", i).as_bytes()))] += 1;
+        }
+        for i in 0..6 {
+            let expected = (n >> (i + 1)) as f64;
+            let tolerance = 5.0 * expected.sqrt();
+            assert!((counts[i] as f64 - expected).abs() < tolerance,
+                    "bucket {} has {} keys, expected about {}", i, counts[i], expected);
+        }
+    }
+
+    /// The linear search closest_nodes used to do, kept to check the bucket search against
+    fn linear_closest(table: &RoutingTable, item: Key, count: usize) -> Vec<NodeAndDistance> {
+        let mut ret = Vec::new();
+        for bucket in &table.buckets {
+            for node_info in bucket {
+                ret.push( NodeAndDistance(node_info.clone(), node_info.id.dist(item)) );
+            }
+        }
+        ret.sort_by(|a,b| a.1.cmp(&b.1));
+        ret.truncate(count);
+        ret
+    }
+
+    fn table_with(k_param: usize, nodes: usize) -> RoutingTable {
+        let mut table = RoutingTable::new(node(0), k_param);
+        for i in 1..nodes {
+            table.update(node(i));
+        }
+        table
+    }
+
+    #[test]
+    fn closest_nodes_matches_linear_search() {
+        for &(k_param, nodes) in &[(1, 50), (8, 20), (8, 2000), (20, 500), (100, 3000)] {
+            let table = table_with(k_param, nodes);
+            for t in 0..200 {
+                // Targets both at random and at the ids of nodes in the table
+                let target = if t % 2 == 0 {
+                    Key::hash(format!("target {}", t).as_bytes())
+                } else {
+                    node(t % nodes).id
+                };
+                for &count in &[0, 1, 3, k_param, 2 * k_param + 1, nodes + 1] {
+                    let ids = |entries: Vec<NodeAndDistance>| {
+                        entries.into_iter().map(|entry| entry.0.id).collect::<Vec<_>>()
+                    };
+                    assert_eq!(ids(table.closest_nodes(target, count)),
+                               ids(linear_closest(&table, target, count)),
+                               "k_param {}, {} nodes, target {:?}, count {}",
+                               k_param, nodes, target, count);
+                }
+            }
+        }
+    }
+
+    /// Compares closest_nodes with the linear search on a table of thousands of contacts. Run
+    /// with cargo test --release -- --ignored --nocapture closest_nodes_benchmark
+    #[test]
+    #[ignore]
+    fn closest_nodes_benchmark() {
+        let table = table_with(1000, 10000);
+        let contacts = table.buckets.iter().map(|bucket| bucket.len()).sum::<usize>();
+        let targets = (0..1000).map(|t| Key::hash(format!("target {}", t).as_bytes()))
+                               .collect::<Vec<_>>();
+
+        let start = Instant::now();
+        for &target in &targets {
+            assert_eq!(table.closest_nodes(target, 8).len(), 8);
+        }
+        let bucketed = start.elapsed();
+
+        let start = Instant::now();
+        for &target in &targets {
+            assert_eq!(linear_closest(&table, target, 8).len(), 8);
+        }
+        let linear = start.elapsed();
+
+        println!("{} lookups among {} contacts: bucket search {:?}, linear search {:?}",
+                 targets.len(), contacts, bucketed, linear);
+    }
+}
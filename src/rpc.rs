@@ -1,24 +1,35 @@
 use std::collections::HashMap;
-use std::net::UdpSocket;
-use std::str;
-use std::sync::{Arc,Mutex};
-use std::sync::mpsc;
-use std::sync::mpsc::{Receiver,Sender};
+use std::io;
+use std::sync::{Arc,Mutex,RwLock};
+use std::sync::atomic::{AtomicBool,Ordering};
+use std::sync::mpsc::Sender;
 use std::thread;
+use std::thread::JoinHandle;
 use std::time::Duration;
-use rustc_serialize::json;
 
-use ::{MESSAGE_LEN,TIMEOUT};
+use ::NETWORK_VERSION;
+use ::clock::Clock;
+use ::codec::Codec;
+use ::config::Config;
+use ::error::{Error,Result};
 use ::kademlia::{Reply,Request};
 use ::key::Key;
+use ::pool::join_thread;
+use ::promise::{Promise,Resolver};
+use ::rng::Rng;
 use ::routing::NodeInfo;
+use ::transport::Transport;
+
+/// Longest the receive loop waits for a datagram before checking whether the Rpc has shut down
+const POLL_INTERVAL: u64 = 500;
 
 #[derive(Clone,Debug,RustcEncodable,RustcDecodable)]
 pub struct RpcMessage {
-    token: Key,
-    src: NodeInfo,
-    dst: NodeInfo,
-    msg: Message,
+    pub version: u8,
+    pub token: Key,
+    pub src: NodeInfo,
+    pub dst: NodeInfo,
+    pub msg: Message,
 }
 
 #[derive(Clone,Debug,RustcEncodable,RustcDecodable)]
@@ -44,52 +55,87 @@ impl ReqHandle {
     }
     pub fn rep(self, rep: Reply) {
         let rep_rmsg = RpcMessage {
+            version: NETWORK_VERSION,
             token: self.token,
             src: self.rpc.node_info.clone(),
             dst: self.src.clone(),
             msg: Message::Reply(rep),
         };
-        self.rpc.send_msg(&rep_rmsg, &self.src.addr);
+        if let Err(e) = self.rpc.send_msg(&rep_rmsg, &self.src.addr) {
+            warn!("Failed to send reply to {}: {}", self.src.addr, e);
+        }
     }
 }
 
 #[derive(Clone)]
 pub struct Rpc {
-    socket: Arc<UdpSocket>,
-    pending: Arc<Mutex<HashMap<Key,Sender<Option<Reply>>>>>,
+    /// Taken when the Rpc shuts down, releasing the transport once the receive loop exits
+    transport: Arc<RwLock<Option<Arc<dyn Transport>>>>,
+    closed: Arc<AtomicBool>,
+    rx_thread: Arc<Mutex<Option<JoinHandle<()>>>>,
+    /// Channel of incoming requests, closed when the Rpc shuts down
+    tx: Arc<Mutex<Option<Sender<ReqHandle>>>>,
+    pending: Arc<Mutex<HashMap<Key,Resolver<Reply>>>>,
     node_info: NodeInfo,
+    clock: Arc<dyn Clock>,
+    rng: Arc<dyn Rng>,
+    codec: Arc<dyn Codec>,
+    timeout: u64,
+    message_len: usize,
 }
 
 impl Rpc {
-    pub fn open(socket: UdpSocket, tx: Sender<ReqHandle>, node_info: NodeInfo) -> Rpc {
+    pub fn open<T: Transport>(transport: T, clock: Arc<dyn Clock>, rng: Arc<dyn Rng>,
+                              tx: Sender<ReqHandle>, node_info: NodeInfo, config: &Config) -> Rpc {
+        let transport: Arc<dyn Transport> = Arc::new(transport);
         let rpc = Rpc {
-            socket: Arc::new(socket),
+            transport: Arc::new(RwLock::new(Some(transport.clone()))),
+            closed: Arc::new(AtomicBool::new(false)),
+            rx_thread: Arc::new(Mutex::new(None)),
+            tx: Arc::new(Mutex::new(Some(tx))),
             pending: Arc::new(Mutex::new(HashMap::new())),
             node_info: node_info,
+            clock: clock,
+            rng: rng,
+            codec: Arc::from(config.wire_format.codec()),
+            timeout: config.timeout,
+            message_len: config.message_len,
         };
+        if let Err(e) = transport.set_read_timeout(Some(Duration::from_millis(POLL_INTERVAL))) {
+            warn!("Failed to set read timeout, shutdown may hang: {}", e);
+        }
         let ret = rpc.clone();
-        thread::spawn(move || {
-            let mut buf = [0u8; MESSAGE_LEN];
+        let rx_thread = thread::spawn(move || {
+            let mut buf = vec![0u8; rpc.message_len];
             loop {
-                let (len, src_addr) = rpc.socket.recv_from(&mut buf).unwrap();
-                let buf_str = String::from(str::from_utf8(&buf[..len]).unwrap());
-                let mut rmsg = json::decode::<RpcMessage>(&buf_str).unwrap();
-                rmsg.src.addr = src_addr.to_string();
+                let recv_res = transport.recv_from(&mut buf);
+                if rpc.closed.load(Ordering::SeqCst) {
+                    break;
+                }
+                let (len, src_addr) = match recv_res {
+                    Ok(res) => res,
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock ||
+                                  e.kind() == io::ErrorKind::TimedOut => {
+                        continue;
+                    }
+                    Err(e) => {
+                        warn!("Failed to receive message: {}", e);
+                        continue;
+                    }
+                };
+                let rmsg = match rpc.decode_msg(&buf[..len], src_addr.clone()) {
+                    Ok(rmsg) => rmsg,
+                    Err(e) => {
+                        warn!("Ignoring message from {}: {}", src_addr, e);
+                        continue;
+                    }
+                };
 
                 debug!("|  IN | {:?} <== {:?} ", rmsg.msg, rmsg.src.id);
 
-                if rmsg.src.net_id != rpc.node_info.net_id {
-                    warn!("Message from different net_id received, ignoring.");
-                    continue;
-                }
-                if rmsg.dst.id != rpc.node_info.id {
-                    warn!("Message received, but dst id does not match this node, ignoring.");
-                    continue;
-                }
-
                 match rmsg.msg {
                     Message::Kill => {
-                        break;
+                        warn!("Kill message received while running, ignoring.");
                     }
                     Message::Request(req) => {
                         let req_handle = ReqHandle {
@@ -98,73 +144,143 @@ impl Rpc {
                             req: req,
                             rpc: rpc.clone(),
                         };
-                        if let Err(_) = tx.send(req_handle) {
+                        let sent = match *rpc.tx.lock().unwrap() {
+                            Some(ref tx) => tx.send(req_handle).is_ok(),
+                            None => false,
+                        };
+                        if !sent {
                             info!("Closing channel, since receiver is dead.");
                             break;
                         }
                     }
                     Message::Reply(rep) => {
-                        rpc.clone().handle_rep(rmsg.token, rep);
+                        rpc.handle_rep(rmsg.token, rep);
                     }
                 }
             }
+            debug!("Receive loop stopped.");
         });
+        *ret.rx_thread.lock().unwrap() = Some(rx_thread);
         ret
     }
 
-    /// Passes a reply received through the Rpc socket to the appropriate pending Receiver
-    fn handle_rep(self, token: Key, rep: Reply) {
-        thread::spawn(move || {
-            let mut pending = self.pending.lock().unwrap();
-            let send_res = match pending.get(&token) {
-                Some(tx) => {
-                    tx.send(Some(rep))
-                }
-                None => {
-                    warn!("Unsolicited reply received, ignoring.");
-                    return;
-                }
-            };
-            if let Ok(_) = send_res {
-                pending.remove(&token);
+    /// Stops the receive loop, failing all pending requests, and releases the transport
+    ///
+    /// Dropping the Rpc's Sender also closes the channel of incoming requests. Called on the
+    /// receive loop's own thread, the loop exits once the current message is handled.
+    pub fn shutdown(&self) {
+        if self.closed.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        self.tx.lock().unwrap().take();
+
+        // Wake the receive loop up with a message to ourselves, so it sees that we're closed
+        // right away rather than at its next read timeout
+        let kill = RpcMessage {
+            version: NETWORK_VERSION,
+            token: Key::random_from(&*self.rng),
+            src: self.node_info.clone(),
+            dst: self.node_info.clone(),
+            msg: Message::Kill,
+        };
+        if let Err(e) = self.send_msg(&kill, &self.node_info.addr) {
+            warn!("Failed to send kill message: {}", e);
+        }
+        if let Some(rx_thread) = self.rx_thread.lock().unwrap().take() {
+            join_thread(rx_thread);
+        }
+        *self.transport.write().unwrap() = None;
+
+        let pending = self.pending.lock().unwrap().drain().collect::<Vec<_>>();
+        for (_, resolver) in pending {
+            resolver.resolve(Err(Error::Shutdown));
+        }
+    }
+
+    /// Decodes a received message, checking that it is meant for this node
+    fn decode_msg(&self, buf: &[u8], src_addr: String) -> Result<RpcMessage> {
+        let mut rmsg = try!(self.codec.decode(buf));
+        rmsg.src.addr = src_addr;
+
+        if rmsg.src.net_id != self.node_info.net_id {
+            return Err(Error::WrongNetId(rmsg.src.net_id));
+        }
+        if rmsg.dst.id != self.node_info.id {
+            return Err(Error::WrongDst);
+        }
+        Ok(rmsg)
+    }
+
+    /// Completes the pending request that a reply received through the Rpc socket belongs to
+    fn handle_rep(&self, token: Key, rep: Reply) {
+        let resolver = self.pending.lock().unwrap().remove(&token);
+        match resolver {
+            Some(resolver) => {
+                resolver.resolve(Ok(rep));
             }
-        });
+            None => {
+                warn!("Unsolicited reply received, ignoring.");
+            }
+        }
     }
 
     /// Sends a message
-    fn send_msg(&self, rmsg: &RpcMessage, addr: &str) {
-        let enc_msg = json::encode(rmsg).unwrap();
-        self.socket.send_to(&enc_msg.as_bytes(), addr).unwrap();
+    fn send_msg(&self, rmsg: &RpcMessage, addr: &str) -> Result<()> {
+        let transport = match *self.transport.read().unwrap() {
+            Some(ref transport) => transport.clone(),
+            None => return Err(Error::Shutdown),
+        };
+        let enc_msg = try!(self.codec.encode(rmsg));
+        if enc_msg.len() > self.message_len {
+            return Err(Error::Encode(format!("message is {} bytes long, more than the max of {}",
+                                             enc_msg.len(), self.message_len)));
+        }
+        try!(transport.send_to(&enc_msg, addr).map_err(Error::Io));
         debug!("| OUT | {:?} ==> {:?} ", rmsg.msg, rmsg.dst.id);
+        Ok(())
     }
 
-    /// Sends a request of data from src_info to dst_info, returning a Receiver for the reply
-    pub fn send_req(&self, req: Request, dst: NodeInfo) -> Receiver<Option<Reply>> {
-        let (tx, rx) = mpsc::channel();
+    /// Sends a request of data from src_info to dst_info, returning a Promise of the reply
+    ///
+    /// If the request can't be sent, or no reply arrives within the timeout, the Promise fails
+    /// instead. Sending never blocks.
+    pub fn send_req(&self, req: Request, dst: NodeInfo) -> Promise<Reply> {
+        let (promise, resolver) = Promise::pair();
         let mut pending = self.pending.lock().unwrap();
-        let mut token = Key::random();
+        let mut token = Key::random_from(&*self.rng);
         while pending.contains_key(&token) {
-            token = Key::random();
+            token = Key::random_from(&*self.rng);
         }
-        pending.insert(token, tx.clone());
+        pending.insert(token, resolver);
         drop(pending);
 
-        let rmsg = RpcMessage { 
+        let rmsg = RpcMessage {
+            version: NETWORK_VERSION,
             token: token,
             src: self.node_info.clone(),
             dst: dst,
             msg: Message::Request(req),
         };
-        self.send_msg(&rmsg, &rmsg.dst.addr);
-
-        let rpc = self.clone();
-        thread::spawn(move || {
-            thread::sleep(Duration::from_millis(TIMEOUT));
-            if let Ok(_) = tx.send(None) {
-                let mut pending = rpc.pending.lock().unwrap();
-                pending.remove(&token);
+        if let Err(e) = self.send_msg(&rmsg, &rmsg.dst.addr) {
+            let resolver = self.pending.lock().unwrap().remove(&token);
+            if let Some(resolver) = resolver {
+                resolver.resolve(Err(e));
             }
-        });
-        rx
+            return promise;
+        }
+
+        // Only a weak reference, so waiting timeouts don't keep a shut down Rpc alive
+        let pending = Arc::downgrade(&self.pending);
+        self.clock.schedule(Duration::from_millis(self.timeout), Box::new(move || {
+            let pending = match pending.upgrade() {
+                Some(pending) => pending,
+                None => { return; }
+            };
+            let resolver = pending.lock().unwrap().remove(&token);
+            if let Some(resolver) = resolver {
+                resolver.resolve(Err(Error::Timeout));
+            }
+        }));
+        promise
     }
 }
@@ -0,0 +1,188 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc,Mutex};
+use std::sync::mpsc;
+use std::sync::mpsc::{Receiver,RecvTimeoutError,Sender};
+use std::time::Duration;
+
+use ::clock::Clock;
+use ::rng::{SeededRng,SplitMix64};
+use ::transport::Transport;
+
+type Datagram = (Vec<u8>, String);
+
+/// An in-process network that routes datagrams between SimTransports
+///
+/// Latency, packet loss and partitions are configurable at any time, and packet loss is drawn from
+/// a generator seeded at creation, as are the Rngs handed to nodes by rng. Delayed datagrams are
+/// delivered through clock, so with a VirtualClock, a run that drives its nodes one operation at a
+/// time is reproduced exactly from its seed.
+#[derive(Clone)]
+pub struct SimNetwork {
+    state: Arc<Mutex<SimState>>,
+    clock: Arc<dyn Clock>,
+}
+
+struct SimState {
+    nodes: HashMap<String, Sender<Datagram>>,
+    next_port: u64,
+    latency: Duration,
+    loss: f64,
+    groups: HashMap<String, usize>,
+    next_group: usize,
+    rng: SplitMix64,
+}
+
+impl SimNetwork {
+    pub fn new(seed: u64, clock: Arc<dyn Clock>) -> SimNetwork {
+        SimNetwork {
+            state: Arc::new(Mutex::new(SimState {
+                nodes: HashMap::new(),
+                next_port: 1,
+                latency: Duration::from_millis(0),
+                loss: 0.0,
+                groups: HashMap::new(),
+                next_group: 1,
+                rng: SplitMix64(seed),
+            })),
+            clock: clock,
+        }
+    }
+
+    /// Returns an Rng for a node, seeded from the network's generator
+    pub fn rng(&self) -> SeededRng {
+        SeededRng::new(self.state.lock().unwrap().rng.next_u64())
+    }
+
+    /// Attaches a new endpoint to the network with a fresh address
+    pub fn bind(&self) -> SimTransport {
+        let (tx, rx) = mpsc::channel();
+        let mut state = self.state.lock().unwrap();
+        let addr = format!("sim:{}", state.next_port);
+        state.next_port += 1;
+        state.nodes.insert(addr.clone(), tx);
+        SimTransport {
+            addr: addr,
+            rx: Mutex::new(rx),
+            read_timeout: Mutex::new(None),
+            net: self.clone(),
+        }
+    }
+
+    /// Sets the one-way delay applied to every delivered datagram
+    pub fn set_latency(&self, latency: Duration) {
+        self.state.lock().unwrap().latency = latency;
+    }
+
+    /// Sets the probability, between 0 and 1, that a datagram is silently dropped
+    pub fn set_loss(&self, loss: f64) {
+        self.state.lock().unwrap().loss = loss;
+    }
+
+    /// Cuts addrs off from every node outside of addrs
+    pub fn partition(&self, addrs: &[String]) {
+        let mut state = self.state.lock().unwrap();
+        let group = state.next_group;
+        state.next_group += 1;
+        for addr in addrs {
+            state.groups.insert(addr.clone(), group);
+        }
+    }
+
+    /// Removes all partitions
+    pub fn heal(&self) {
+        self.state.lock().unwrap().groups.clear();
+    }
+
+    fn deliver(&self, buf: &[u8], src: &str, dst: &str) {
+        let mut state = self.state.lock().unwrap();
+        if src == dst {
+            // Datagrams a node sends to itself never hit the simulated network
+            if let Some(tx) = state.nodes.get(dst) {
+                let _ = tx.send((buf.to_vec(), String::from(src)));
+            }
+            return;
+        }
+        if state.groups.get(src) != state.groups.get(dst) {
+            debug!("Dropping datagram from {} to {} across partition.", src, dst);
+            return;
+        }
+        if state.loss > 0.0 && state.rng.next_f64() < state.loss {
+            debug!("Dropping datagram from {} to {}.", src, dst);
+            return;
+        }
+        let tx = match state.nodes.get(dst) {
+            Some(tx) => tx.clone(),
+            None => {
+                debug!("Dropping datagram to unknown address {}.", dst);
+                return;
+            }
+        };
+        let latency = state.latency;
+        drop(state);
+
+        let datagram = (buf.to_vec(), String::from(src));
+        if latency == Duration::from_millis(0) {
+            let _ = tx.send(datagram);
+        } else {
+            self.clock.schedule(latency, Box::new(move || {
+                let _ = tx.send(datagram);
+            }));
+        }
+    }
+}
+
+/// One endpoint of a SimNetwork
+pub struct SimTransport {
+    addr: String,
+    rx: Mutex<Receiver<Datagram>>,
+    /// Real time that recv_from waits for, independent of the network's clock
+    read_timeout: Mutex<Option<Duration>>,
+    net: SimNetwork,
+}
+
+impl Transport for SimTransport {
+    fn send_to(&self, buf: &[u8], addr: &str) -> io::Result<usize> {
+        self.net.deliver(buf, &self.addr, addr);
+        Ok(buf.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, String)> {
+        let timeout = *self.read_timeout.lock().unwrap();
+        let rx = self.rx.lock().unwrap();
+        let res = match timeout {
+            Some(timeout) => rx.recv_timeout(timeout),
+            None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match res {
+            Ok((data, src)) => {
+                let len = data.len().min(buf.len());
+                buf[..len].copy_from_slice(&data[..len]);
+                Ok((len, src))
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                Err(io::Error::new(io::ErrorKind::TimedOut, "no datagram received"))
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                Err(io::Error::new(io::ErrorKind::BrokenPipe, "simulated network closed"))
+            }
+        }
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        *self.read_timeout.lock().unwrap() = timeout;
+        Ok(())
+    }
+
+    fn local_addr(&self) -> io::Result<String> {
+        Ok(self.addr.clone())
+    }
+}
+
+impl Drop for SimTransport {
+    fn drop(&mut self) {
+        let mut state = self.net.state.lock().unwrap();
+        state.nodes.remove(&self.addr);
+        state.groups.remove(&self.addr);
+    }
+}
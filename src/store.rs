@@ -0,0 +1,95 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use ::error::Result;
+
+/// A value held in a node's record store
+#[derive(Clone,Debug)]
+pub struct Record {
+    pub value: Vec<u8>,
+    /// Time the value was last stored or republished, on the node's clock
+    pub stored_at: Duration,
+    /// Time after which the value is dropped, if it was stored with a TTL
+    pub expires: Option<Duration>,
+}
+
+impl Record {
+    pub fn is_expired(&self, now: Duration) -> bool {
+        match self.expires {
+            Some(expires) => now >= expires,
+            None => false,
+        }
+    }
+}
+
+/// Storage for the values a node holds on behalf of the network
+///
+/// The node keeps its store behind a lock, so implementations don't need to synchronize
+/// themselves. Records are handed out by value, so a store is free to keep them anywhere, such as
+/// on disk or in a cache it updates on every read.
+pub trait RecordStore: Send {
+    /// Returns the record at k
+    fn get(&mut self, k: &[u8]) -> Result<Option<Record>>;
+
+    /// Stores record at k, replacing any record already there
+    fn put(&mut self, k: Vec<u8>, record: Record) -> Result<()>;
+
+    /// Removes the record at k, returning it
+    fn remove(&mut self, k: &[u8]) -> Result<Option<Record>>;
+
+    /// Returns the keys of every record, expired or not
+    fn keys(&mut self) -> Result<Vec<Vec<u8>>>;
+
+    /// Removes every record that has expired at now, returning how many there were
+    fn expire(&mut self, now: Duration) -> Result<usize>;
+
+    /// Returns the number of records held
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// A RecordStore holding its records in memory, which is what nodes use by default
+#[derive(Debug,Default)]
+pub struct MemoryStore {
+    records: HashMap<Vec<u8>, Record>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore {
+            records: HashMap::new(),
+        }
+    }
+}
+
+impl RecordStore for MemoryStore {
+    fn get(&mut self, k: &[u8]) -> Result<Option<Record>> {
+        Ok(self.records.get(k).cloned())
+    }
+
+    fn put(&mut self, k: Vec<u8>, record: Record) -> Result<()> {
+        self.records.insert(k, record);
+        Ok(())
+    }
+
+    fn remove(&mut self, k: &[u8]) -> Result<Option<Record>> {
+        Ok(self.records.remove(k))
+    }
+
+    fn keys(&mut self) -> Result<Vec<Vec<u8>>> {
+        Ok(self.records.keys().cloned().collect())
+    }
+
+    fn expire(&mut self, now: Duration) -> Result<usize> {
+        let before = self.records.len();
+        self.records.retain(|_, record| !record.is_expired(now));
+        Ok(before - self.records.len())
+    }
+
+    fn len(&self) -> usize {
+        self.records.len()
+    }
+}
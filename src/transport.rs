@@ -0,0 +1,43 @@
+use std::io;
+use std::net::UdpSocket;
+use std::time::Duration;
+
+/// A datagram transport that an `Rpc` can send and receive messages over
+pub trait Transport: Send + Sync + 'static {
+    /// Sends a datagram to addr, returning the number of bytes sent
+    fn send_to(&self, buf: &[u8], addr: &str) -> io::Result<usize>;
+
+    /// Blocks until a datagram is received, returning its length and source address
+    ///
+    /// If a read timeout is set and no datagram arrives within it, fails with an error of kind
+    /// WouldBlock or TimedOut.
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, String)>;
+
+    /// Sets how long recv_from waits for a datagram, or None to wait forever
+    ///
+    /// The Rpc sets a timeout so that its receive loop notices a shutdown even if nothing is
+    /// received.
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    /// Returns the address other nodes can reach this transport at
+    fn local_addr(&self) -> io::Result<String>;
+}
+
+impl Transport for UdpSocket {
+    fn send_to(&self, buf: &[u8], addr: &str) -> io::Result<usize> {
+        UdpSocket::send_to(self, buf, addr)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, String)> {
+        let (len, src_addr) = try!(UdpSocket::recv_from(self, buf));
+        Ok((len, src_addr.to_string()))
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UdpSocket::set_read_timeout(self, timeout)
+    }
+
+    fn local_addr(&self) -> io::Result<String> {
+        UdpSocket::local_addr(self).map(|addr| addr.to_string())
+    }
+}
@@ -0,0 +1,251 @@
+extern crate kademlia;
+
+use std::io;
+use std::sync::Arc;
+use std::sync::mpsc;
+use std::sync::atomic::{AtomicBool,Ordering};
+use std::thread;
+use std::time::{Duration,Instant};
+
+use kademlia::*;
+
+const NET_ID: &'static str = "test_net";
+
+/// Returns the deterministic id of node i
+fn node_id(i: usize) -> Key {
+    Key::hash(format!("node {}", i).as_bytes())
+}
+
+/// Starts n nodes on net, each bootstrapping from the first
+fn start_nodes(net: &SimNetwork, clock: &Arc<VirtualClock>, n: usize, config: &Config)
+    -> Vec<Kademlia> {
+    let mut nodes: Vec<Kademlia> = Vec::with_capacity(n);
+    for i in 0..n {
+        // SimNetwork hands out addresses in order, so the first node is at sim:1
+        let bootstrap = if i == 0 {
+            None
+        } else {
+            Some(NodeInfo {
+                id: node_id(0),
+                addr: String::from("sim:1"),
+                net_id: String::from(NET_ID),
+            })
+        };
+        let node = Kademlia::start_with_transport(String::from(NET_ID), node_id(i), net.bind(),
+                                                  clock.clone(), Arc::new(net.rng()),
+                                                  Box::new(MemoryStore::new()), bootstrap,
+                                                  config.clone()).unwrap();
+        nodes.push(node);
+    }
+    nodes
+}
+
+/// Returns the ids of the count nodes closest to target, out of the first n
+fn true_closest(n: usize, target: Key, count: usize) -> Vec<Key> {
+    let mut ids = (0..n).map(node_id).collect::<Vec<_>>();
+    ids.sort_by_key(|id| id.dist(target));
+    ids.truncate(count);
+    ids
+}
+
+#[test]
+fn lookups_find_closest_nodes_among_500() {
+    let n = 500;
+    let clock = Arc::new(VirtualClock::new());
+    let net = SimNetwork::new(1, clock.clone());
+    let config = Config {
+        workers: 1,
+        queue_len: 4096,
+        ..Config::default()
+    };
+    let nodes = start_nodes(&net, &clock, n, &config);
+
+    for t in 0..20 {
+        let target = Key::hash(format!("target {}", t).as_bytes());
+        let expected = true_closest(n, target, config.k_param);
+        let from = &nodes[(t * 37) % n];
+        let found = from.lookup_nodes(target).unwrap().into_iter().map(|entry| entry.0.id)
+                        .collect::<Vec<_>>();
+        assert_eq!(found, expected, "lookup of target {} from node {}", t, (t * 37) % n);
+    }
+}
+
+#[test]
+fn values_survive_repeated_gets() {
+    let clock = Arc::new(VirtualClock::new());
+    let net = SimNetwork::new(1, clock.clone());
+    let nodes = start_nodes(&net, &clock, 3, &Config::default());
+
+    assert_eq!(nodes[0].put(b"key".to_vec(), b"value".to_vec()).unwrap(), 3);
+    for round in 0..10 {
+        for (i, node) in nodes.iter().enumerate() {
+            assert_eq!(node.get(b"key".to_vec()).unwrap(), Some(b"value".to_vec()),
+                       "get {} from node {}", round, i);
+        }
+    }
+}
+
+#[test]
+fn zero_intervals_and_workers_are_rejected() {
+    let clock = Arc::new(VirtualClock::new());
+    let net = SimNetwork::new(1, clock.clone());
+    let configs = vec![
+        Config { sweep_interval: 0, ..Config::default() },
+        Config { refresh_interval: 0, ..Config::default() },
+        Config { republish_interval: 0, ..Config::default() },
+        Config { workers: 0, ..Config::default() },
+        Config { queue_len: 0, ..Config::default() },
+    ];
+    for config in configs {
+        let res = Kademlia::start_with_transport(String::from(NET_ID), node_id(0), net.bind(),
+                                                 clock.clone(), Arc::new(net.rng()),
+                                                 Box::new(MemoryStore::new()), None,
+                                                 config.clone());
+        match res {
+            Err(Error::Config(_)) => {}
+            _ => panic!("{:?} was accepted", config),
+        }
+    }
+}
+
+/// A transport that loses every datagram a node sends to itself, once the flag is set
+struct NoLoopback(SimTransport, Arc<AtomicBool>);
+
+impl Transport for NoLoopback {
+    fn send_to(&self, buf: &[u8], addr: &str) -> io::Result<usize> {
+        if self.1.load(Ordering::SeqCst) && addr == try!(self.0.local_addr()) {
+            return Ok(buf.len());
+        }
+        self.0.send_to(buf, addr)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, String)> {
+        self.0.recv_from(buf)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.0.set_read_timeout(timeout)
+    }
+
+    fn local_addr(&self) -> io::Result<String> {
+        self.0.local_addr()
+    }
+}
+
+#[test]
+fn shutdown_doesnt_depend_on_kill_message() {
+    let clock = Arc::new(VirtualClock::new());
+    let net = SimNetwork::new(1, clock.clone());
+    let no_loopback = Arc::new(AtomicBool::new(false));
+    let node = Kademlia::start_with_transport(String::from(NET_ID), node_id(0),
+                                              NoLoopback(net.bind(), no_loopback.clone()),
+                                              clock.clone(), Arc::new(net.rng()),
+                                              Box::new(MemoryStore::new()), None,
+                                              Config::default()).unwrap();
+    // Lose the Kill message shutdown sends to wake the receive loop
+    no_loopback.store(true, Ordering::SeqCst);
+    let start = Instant::now();
+    node.shutdown();
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
+
+/// A MemoryStore that records when it's dropped
+struct TrackedStore(MemoryStore, Arc<AtomicBool>);
+
+impl RecordStore for TrackedStore {
+    fn get(&mut self, k: &[u8]) -> Result<Option<Record>> {
+        self.0.get(k)
+    }
+
+    fn put(&mut self, k: Vec<u8>, record: Record) -> Result<()> {
+        self.0.put(k, record)
+    }
+
+    fn remove(&mut self, k: &[u8]) -> Result<Option<Record>> {
+        self.0.remove(k)
+    }
+
+    fn keys(&mut self) -> Result<Vec<Vec<u8>>> {
+        self.0.keys()
+    }
+
+    fn expire(&mut self, now: Duration) -> Result<usize> {
+        self.0.expire(now)
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+}
+
+impl Drop for TrackedStore {
+    fn drop(&mut self) {
+        self.1.store(true, Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn shutdown_releases_node_before_timers_fire() {
+    let clock = Arc::new(VirtualClock::new());
+    let net = SimNetwork::new(1, clock.clone());
+    let dropped = Arc::new(AtomicBool::new(false));
+    let store = TrackedStore(MemoryStore::new(), dropped.clone());
+    let node = Kademlia::start_with_transport(String::from(NET_ID), node_id(0), net.bind(),
+                                              clock.clone(), Arc::new(net.rng()),
+                                              Box::new(store), None, Config::default()).unwrap();
+    node.shutdown();
+    drop(node);
+    // The maintenance timers are still waiting, but no longer hold on to the node
+    assert!(clock.pending() > 0);
+    assert!(dropped.load(Ordering::SeqCst));
+}
+
+/// Returns the first datagram a fresh node on a network seeded with seed sends to a ping
+fn first_ping(seed: u64) -> Vec<u8> {
+    let clock = Arc::new(VirtualClock::new());
+    let net = SimNetwork::new(seed, clock.clone());
+    let node = start_nodes(&net, &clock, 1, &Config::default()).remove(0);
+    let peer = net.bind();
+    let dst = NodeInfo {
+        id: node_id(1),
+        addr: peer.local_addr().unwrap(),
+        net_id: String::from(NET_ID),
+    };
+    let _reply = node.ping_raw(dst);
+    let mut buf = [0u8; 1024];
+    let (len, _) = peer.recv_from(&mut buf).unwrap();
+    node.shutdown();
+    buf[..len].to_vec()
+}
+
+#[test]
+fn request_tokens_are_reproduced_from_the_seed() {
+    assert_eq!(first_ping(7), first_ping(7));
+    assert!(first_ping(7) != first_ping(8));
+}
+
+#[test]
+fn last_handle_can_be_dropped_on_the_nodes_own_thread() {
+    let clock = Arc::new(VirtualClock::new());
+    let net = SimNetwork::new(1, clock.clone());
+    let mut nodes = start_nodes(&net, &clock, 2, &Config::default());
+    let node = nodes.remove(0);
+    let peer = NodeInfo {
+        id: node_id(1),
+        addr: String::from("sim:2"),
+        net_id: String::from(NET_ID),
+    };
+    // Delay the ping and its reply, so the reply resolves the promise on the receive loop
+    net.set_latency(Duration::from_millis(10));
+    let (tx, rx) = mpsc::channel();
+    let reply = node.ping_raw(peer);
+    reply.then(move |res| {
+        drop(node);
+        tx.send(res.is_ok()).unwrap();
+    });
+    for _ in 0..100 {
+        clock.advance(Duration::from_millis(10));
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(rx.try_recv(), Ok(true));
+}